@@ -1,7 +1,7 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use typst_pdf_api::templates::{
-    template_to_pdf, 
-    german_invoice::{GermanTemplateData, InvoiceItem, Author, Client, BankAccount, Address}
+    template_to_pdf, template_to_pdf_with_options, RenderOptions,
+    german_invoice::{GermanTemplateData, InvoiceItem, Author, Client, BankAccount, Address, GERMAN_INVOICE_TEMPLATE}
 };
 
 fn bench_simple_pdf_generation(c: &mut Criterion) {
@@ -15,9 +15,12 @@ fn bench_simple_pdf_generation(c: &mut Criterion) {
 fn bench_german_invoice_pdf_generation(c: &mut Criterion) {
     c.bench_function("german_invoice_pdf_generation", |b| {
         b.iter(|| {
-            let data = create_test_data();
-            let template = data.into_typst_template();
-            template_to_pdf(template).expect("German invoice PDF generation failed")
+            let options = RenderOptions {
+                data: Some(create_test_data().into_template_data()),
+                ..Default::default()
+            };
+            template_to_pdf_with_options(GERMAN_INVOICE_TEMPLATE.to_string(), options)
+                .expect("German invoice PDF generation failed")
         })
     });
 }
@@ -26,7 +29,7 @@ fn bench_template_generation_only(c: &mut Criterion) {
     c.bench_function("template_generation_only", |b| {
         b.iter(|| {
             let data = create_test_data();
-            data.into_typst_template()
+            data.into_template_data()
         })
     });
 }
@@ -0,0 +1,159 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use typst::syntax::package::PackageSpec;
+use typst_pdf_api::TypstWrapperWorld;
+use typst_pdf_api::packages::{Lockfile, PackageConfig, PackageStorage};
+use typst_pdf_api::templates::registry::TemplateRegistry;
+
+/// Lockfile written by `lock` unless a path is given or `PACKAGE_LOCKFILE` is set.
+const DEFAULT_LOCKFILE: &str = "typst-packages.lock";
+
+/// `lock [PATH]`: records the archive hashes of all packages imported by the registered
+/// templates and, transitively, by those packages, keeping the entries of other packages in an
+/// existing lockfile.
+pub fn lock(registry: &TemplateRegistry, path: Option<String>) -> ExitCode {
+    let config = PackageConfig::from_env();
+    let path = path
+        .map(PathBuf::from)
+        .or_else(|| config.lockfile.clone())
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOCKFILE));
+
+    let mut lockfile = if path.exists() {
+        match Lockfile::load(&path) {
+            Ok(lockfile) => lockfile,
+            Err(error) => {
+                eprintln!("Failed to read {}: {error}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        Lockfile::default()
+    };
+
+    // The hashes are taken from the registry, so the current lockfile must not be enforced.
+    let storage = PackageStorage::new(PackageConfig {
+        lockfile: None,
+        ..config
+    });
+    let mut pending = VecDeque::from(registry.packages());
+    let mut locked = HashSet::new();
+    while let Some(package) = pending.pop_front() {
+        if !locked.insert(package.clone()) {
+            continue;
+        }
+        match storage.inspect_archive(&package) {
+            Ok(archive) => {
+                println!("{package} {}", archive.hash);
+                lockfile.insert(&package, archive.hash);
+                pending.extend(archive.imports);
+            }
+            Err(error) => {
+                eprintln!("Failed to download {package}: {error}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if let Err(error) = lockfile.save(&path) {
+        eprintln!("Failed to write {}: {error}", path.display());
+        return ExitCode::FAILURE;
+    }
+    println!("Wrote {}", path.display());
+    ExitCode::SUCCESS
+}
+
+/// `cache list`, `cache purge SPEC` and `cache evict`, on the cache configured by the environment.
+///
+/// `evict` enforces `PACKAGE_CACHE_MAX_SIZE`, keeping the packages of the registered templates.
+pub fn cache(registry: &TemplateRegistry, args: Vec<String>) -> ExitCode {
+    let storage = PackageStorage::new(PackageConfig::from_env());
+    storage.pin(&registry.packages());
+
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["list"] => storage.cached_packages().map(|cached| {
+            for cached in cached {
+                println!("{}\t{}\t{}", cached.package, cached.size, cached.last_used);
+            }
+        }),
+        ["purge", spec] => {
+            let package: PackageSpec = match spec.parse() {
+                Ok(package) => package,
+                Err(error) => {
+                    eprintln!("Invalid package `{spec}`: {error}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            storage.purge(&package).map(|purged| {
+                if purged {
+                    println!("Purged {package}");
+                } else {
+                    println!("{package} is not cached");
+                }
+            })
+        }
+        ["evict"] => storage.enforce_cache_limit(None).map(|evicted| {
+            for cached in &evicted {
+                println!("Evicted {} ({} bytes)", cached.package, cached.size);
+            }
+            println!("Evicted {} package(s)", evicted.len());
+        }),
+        _ => {
+            eprintln!("Expected `cache list`, `cache purge SPEC` or `cache evict`");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Failed to access the package cache: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `fonts` lists the fonts available to templates, `fonts coverage FAMILY TEXT` prints the
+/// characters of `TEXT` that `FAMILY` has no glyph for.
+pub fn fonts(args: Vec<String>) -> ExitCode {
+    let world = TypstWrapperWorld::with_source(String::new());
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => {
+            for entry in world.font_entries() {
+                let variant = entry.face.variant;
+                let source = match (&entry.path, entry.embedded) {
+                    (Some(path), _) => path.display().to_string(),
+                    (None, true) => "embedded".to_owned(),
+                    (None, false) => "registered".to_owned(),
+                };
+                println!(
+                    "{}\t{:?} {} {:?}\t{source}",
+                    entry.face.family,
+                    variant.style,
+                    variant.weight.to_number(),
+                    variant.stretch.to_ratio()
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        ["coverage", family, text] => {
+            let coverage = world.font_coverage(family, text);
+            if !coverage.available {
+                eprintln!("No font of the family `{family}` is available");
+                ExitCode::FAILURE
+            } else if coverage.covered {
+                println!("`{family}` covers the whole text");
+                ExitCode::SUCCESS
+            } else {
+                let missing: String = coverage.missing.iter().collect();
+                println!("`{family}` has no glyph for: {missing}");
+                ExitCode::FAILURE
+            }
+        }
+        _ => {
+            eprintln!("Expected `fonts` or `fonts coverage FAMILY TEXT`");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use serde::Serialize;
+use typst::foundations::Bytes;
+use typst::text::{Font, FontBook, FontInfo, FontVariant};
+use typst::utils::LazyHash;
+use typst_kit::fonts::{FontSearcher, Fonts};
+
+use crate::templates::AppError;
+
+/// Where the fonts available to templates come from.
+///
+/// Fonts in the font path take precedence over system fonts, which take precedence over the fonts
+/// embedded in the binary (Libertinus Serif, New Computer Modern and DejaVu Sans Mono).
+#[derive(Debug, Clone)]
+pub struct FontConfig {
+    /// Directories searched for fonts, e.g. corporate fonts shipped next to the binary.
+    pub font_path: Vec<PathBuf>,
+
+    /// Also use the fonts installed on the host. Without them, documents only depend on the font
+    /// path and the embedded fonts, so they render the same on every machine.
+    pub system_fonts: bool,
+}
+
+impl Default for FontConfig {
+    fn default() -> Self {
+        Self {
+            font_path: Vec::new(),
+            system_fonts: true,
+        }
+    }
+}
+
+impl FontConfig {
+    /// Reads `FONT_PATH` (directories separated like `PATH`) and `FONTS_EMBEDDED_ONLY`, which
+    /// disables system fonts when set to `1` or `true`.
+    pub fn from_env() -> Self {
+        Self {
+            font_path: std::env::var_os("FONT_PATH")
+                .map(|paths| std::env::split_paths(&paths).collect())
+                .unwrap_or_default(),
+            system_fonts: !std::env::var("FONTS_EMBEDDED_ONLY")
+                .is_ok_and(|value| matches!(value.as_str(), "1" | "true")),
+        }
+    }
+
+    /// Loads the metadata of all configured fonts.
+    pub fn search(&self) -> Fonts {
+        let fonts = FontSearcher::new()
+            .include_system_fonts(self.system_fonts)
+            .include_embedded_fonts(true)
+            .search_with(&self.font_path);
+        tracing::debug!(
+            "Found {} font(s), including system fonts: {}",
+            fonts.fonts.len(),
+            self.system_fonts
+        );
+        fonts
+    }
+}
+
+/// A font face, identified by its family and variant.
+#[derive(Debug, Clone, Serialize)]
+pub struct FontFace {
+    pub family: String,
+    #[serde(flatten)]
+    pub variant: FontVariant,
+}
+
+impl FontFace {
+    pub(crate) fn of(info: &FontInfo) -> Self {
+        Self {
+            family: info.family.clone(),
+            variant: info.variant,
+        }
+    }
+
+    /// Whether both faces have the same variant of the same family, which is matched like Typst
+    /// does, ignoring case.
+    fn is_same(&self, other: &Self) -> bool {
+        self.variant == other.variant && self.family.to_lowercase() == other.family.to_lowercase()
+    }
+}
+
+/// A font available to templates, as listed by [`crate::TypstWrapperWorld::font_entries`].
+#[derive(Debug, Clone, Serialize)]
+pub struct FontEntry {
+    #[serde(flatten)]
+    pub face: FontFace,
+    /// File the font is loaded from, none for embedded fonts and fonts registered at runtime.
+    pub path: Option<PathBuf>,
+    /// Whether the font is compiled into the binary.
+    pub embedded: bool,
+}
+
+/// Whether a font family can display a text, see [`coverage`].
+#[derive(Debug, Serialize)]
+pub struct Coverage {
+    pub family: String,
+    /// Whether any font of the family is available at all.
+    pub available: bool,
+    /// Whether every character of the text has a glyph in one of the family's fonts.
+    pub covered: bool,
+    /// Characters without a glyph in the family, in the order they first appear in the text.
+    pub missing: Vec<char>,
+}
+
+/// Checks which characters of `text` lack a glyph in all fonts of `family` in `book`, and would be
+/// displayed in a fallback font or as a tofu box instead. Control characters are ignored.
+pub fn coverage(book: &FontBook, family: &str, text: &str) -> Coverage {
+    let faces: Vec<_> = book
+        .select_family(&family.to_lowercase())
+        .filter_map(|index| book.info(index))
+        .collect();
+
+    let mut missing = Vec::new();
+    for c in text.chars().filter(|c| !c.is_control()) {
+        if !missing.contains(&c) && !faces.iter().any(|info| info.coverage.contains(c as u32)) {
+            missing.push(c);
+        }
+    }
+
+    Coverage {
+        family: family.to_owned(),
+        available: !faces.is_empty(),
+        covered: missing.is_empty(),
+        missing,
+    }
+}
+
+/// The fonts of one tenant, with a book of the shared fonts followed by the tenant's fonts.
+#[derive(Debug, Clone)]
+pub struct FontSet {
+    pub(crate) book: LazyHash<FontBook>,
+    pub(crate) fonts: Vec<Font>,
+    /// Total size of the font files the fonts were registered from, in bytes.
+    size: usize,
+}
+
+impl FontSet {
+    /// The faces of the tenant's fonts, in the order they were registered.
+    pub fn faces(&self) -> Vec<FontFace> {
+        self.fonts
+            .iter()
+            .map(|font| FontFace::of(font.info()))
+            .collect()
+    }
+}
+
+/// Faces of a font file passed to [`TenantFonts::register`].
+#[derive(Debug, Default, Serialize)]
+pub struct Registration {
+    /// Faces that are now available to the tenant.
+    pub added: Vec<FontFace>,
+    /// Faces skipped because the tenant already has a font with their family and variant.
+    pub duplicates: Vec<FontFace>,
+}
+
+/// Limits of the fonts registered through [`TenantFonts::register`], which are kept in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct TenantFontLimits {
+    /// Maximum number of font faces per tenant.
+    pub max_fonts: usize,
+    /// Maximum total size of the font files of a tenant in bytes.
+    pub max_size: usize,
+    /// Maximum number of tenants with fonts.
+    pub max_tenants: usize,
+}
+
+impl Default for TenantFontLimits {
+    fn default() -> Self {
+        Self {
+            max_fonts: 64,
+            max_size: 50 * 1024 * 1024,
+            max_tenants: 1000,
+        }
+    }
+}
+
+impl TenantFontLimits {
+    /// Reads `TENANT_FONTS_MAX_FONTS`, `TENANT_FONTS_MAX_SIZE` and `TENANT_FONTS_MAX_TENANTS`,
+    /// falling back to the defaults for unset or unparsable values.
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<usize> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+
+        let default = Self::default();
+        Self {
+            max_fonts: var("TENANT_FONTS_MAX_FONTS").unwrap_or(default.max_fonts),
+            max_size: var("TENANT_FONTS_MAX_SIZE").unwrap_or(default.max_size),
+            max_tenants: var("TENANT_FONTS_MAX_TENANTS").unwrap_or(default.max_tenants),
+        }
+    }
+}
+
+/// Fonts registered by tenants at runtime, which are only available to the renders of that tenant.
+///
+/// The books of the tenants extend `base` without searching for fonts again, so they must only
+/// be used by worlds whose fonts are described by `base`.
+#[derive(Debug)]
+pub struct TenantFonts {
+    base: FontBook,
+    limits: TenantFontLimits,
+    tenants: RwLock<HashMap<String, Arc<FontSet>>>,
+}
+
+impl TenantFonts {
+    pub fn new(base: FontBook, limits: TenantFontLimits) -> Self {
+        Self {
+            base,
+            limits,
+            tenants: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The fonts of `tenant`, if it registered any.
+    pub fn get(&self, tenant: &str) -> Option<Arc<FontSet>> {
+        self.tenants
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(tenant)
+            .cloned()
+    }
+
+    /// Registers every face of the font file (TTF, OTF or collection) `data` for `tenant`.
+    ///
+    /// Fails with [`AppError::PayloadTooLarge`] if the new faces would exceed the
+    /// [limits](TenantFontLimits), in which case none of them are registered.
+    pub fn register(&self, tenant: &str, data: Vec<u8>) -> Result<Registration, AppError> {
+        let size = data.len();
+        let faces: Vec<_> = Font::iter(Bytes::new(data)).collect();
+        if faces.is_empty() {
+            return Err(AppError::InvalidData("not a valid font file".to_owned()));
+        }
+
+        let mut tenants = self.tenants.write().unwrap_or_else(PoisonError::into_inner);
+        let existing = tenants.get(tenant);
+        let mut fonts = existing.map(|set| set.fonts.clone()).unwrap_or_default();
+        let size = existing.map_or(0, |set| set.size) + size;
+        let mut registration = Registration::default();
+        for font in faces {
+            let face = FontFace::of(font.info());
+            if fonts
+                .iter()
+                .any(|existing| FontFace::of(existing.info()).is_same(&face))
+            {
+                registration.duplicates.push(face);
+            } else {
+                fonts.push(font);
+                registration.added.push(face);
+            }
+        }
+
+        if !registration.added.is_empty() {
+            let limits = &self.limits;
+            if existing.is_none() && tenants.len() >= limits.max_tenants {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "fonts of at most {} tenants can be registered",
+                    limits.max_tenants
+                )));
+            }
+            if fonts.len() > limits.max_fonts {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "a tenant can register at most {} fonts",
+                    limits.max_fonts
+                )));
+            }
+            if size > limits.max_size {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "the fonts of a tenant can be at most {} bytes",
+                    limits.max_size
+                )));
+            }
+
+            let mut book = self.base.clone();
+            for font in &fonts {
+                book.push(font.info().clone());
+            }
+            let set = FontSet {
+                book: LazyHash::new(book),
+                fonts,
+                size,
+            };
+            tenants.insert(tenant.to_owned(), Arc::new(set));
+        }
+        Ok(registration)
+    }
+
+    /// Removes all fonts of `tenant`, returning whether it had any.
+    pub fn remove(&self, tenant: &str) -> bool {
+        self.tenants
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(tenant)
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_only_fonts_skip_the_system_fonts() {
+        let embedded = FontConfig {
+            system_fonts: false,
+            ..Default::default()
+        }
+        .search();
+        assert!(embedded.fonts.iter().all(|slot| slot.path().is_none()));
+        assert!(embedded.book.contains_family("libertinus serif"));
+
+        // A copy of an embedded font stands in for a corporate font.
+        let font_dir = tempfile::tempdir().expect("temp dir is created");
+        let corporate = font_dir.path().join("corporate.otf");
+        let font = embedded.fonts[0].get().expect("embedded font is loaded");
+        std::fs::write(&corporate, font.data().as_slice()).expect("font is written");
+
+        let fonts = FontConfig {
+            font_path: vec![font_dir.path().to_owned()],
+            system_fonts: false,
+        }
+        .search();
+        assert_eq!(fonts.fonts.len(), embedded.fonts.len() + 1);
+        assert_eq!(fonts.fonts[0].path(), Some(corporate.as_path()));
+        assert!(
+            fonts.fonts[1..].iter().all(|slot| slot.path().is_none()),
+            "only the font path and the embedded fonts are searched"
+        );
+    }
+
+    #[test]
+    fn coverage_reports_missing_characters() {
+        let fonts = FontConfig {
+            system_fonts: false,
+            ..Default::default()
+        }
+        .search();
+
+        let latin = coverage(&fonts.book, "Libertinus Serif", "Jürgen Müller\n");
+        assert!(latin.available);
+        assert!(latin.covered, "{latin:?}");
+
+        let mixed = coverage(&fonts.book, "libertinus serif", "Müller 王伟 王");
+        assert!(!mixed.covered);
+        assert_eq!(mixed.missing, ['王', '伟']);
+
+        let unknown = coverage(&fonts.book, "Comic Sans MS", "a");
+        assert!(!unknown.available);
+        assert_eq!(unknown.missing, ['a']);
+    }
+
+    #[test]
+    fn tenant_fonts_are_merged_and_deduplicated() {
+        let shared = FontConfig {
+            system_fonts: false,
+            ..Default::default()
+        }
+        .search();
+        let font = shared.fonts[0].get().expect("embedded font is loaded");
+        let data = font.data().as_slice().to_vec();
+
+        let tenant_fonts = TenantFonts::new(shared.book.clone(), TenantFontLimits::default());
+        let registration = tenant_fonts
+            .register("acme", data.clone())
+            .expect("font is registered");
+        assert_eq!(registration.added.len(), 1);
+        assert!(registration.duplicates.is_empty());
+
+        let registration = tenant_fonts
+            .register("acme", data)
+            .expect("font is registered");
+        assert!(registration.added.is_empty());
+        assert_eq!(registration.duplicates[0].family, font.info().family);
+
+        let set = tenant_fonts.get("acme").expect("tenant has fonts");
+        assert_eq!(set.fonts.len(), 1);
+        assert_eq!(
+            set.book.info(shared.fonts.len()),
+            Some(font.info()),
+            "tenant fonts follow the shared fonts"
+        );
+        assert!(tenant_fonts.get("other").is_none());
+
+        assert!(
+            tenant_fonts
+                .register("acme", b"not a font".to_vec())
+                .is_err()
+        );
+        assert!(tenant_fonts.remove("acme"));
+        assert!(tenant_fonts.get("acme").is_none());
+    }
+
+    #[test]
+    fn tenant_fonts_are_limited() {
+        let shared = FontConfig {
+            system_fonts: false,
+            ..Default::default()
+        }
+        .search();
+        let data = |index: usize| {
+            let font = shared.fonts[index].get().expect("embedded font is loaded");
+            font.data().as_slice().to_vec()
+        };
+        let too_large = |result: Result<Registration, AppError>| {
+            matches!(result, Err(AppError::PayloadTooLarge(_)))
+        };
+
+        let tenant_fonts = TenantFonts::new(
+            shared.book.clone(),
+            TenantFontLimits {
+                max_fonts: 1,
+                max_tenants: 2,
+                ..Default::default()
+            },
+        );
+        tenant_fonts
+            .register("acme", data(0))
+            .expect("font is registered");
+        assert!(too_large(tenant_fonts.register("acme", data(1))));
+        tenant_fonts
+            .register("acme", data(0))
+            .expect("duplicates don't count against the limit");
+        assert_eq!(
+            tenant_fonts
+                .get("acme")
+                .expect("tenant has fonts")
+                .fonts
+                .len(),
+            1
+        );
+
+        tenant_fonts
+            .register("globex", data(1))
+            .expect("font is registered");
+        assert!(too_large(tenant_fonts.register("initech", data(0))));
+        assert!(tenant_fonts.get("initech").is_none());
+        tenant_fonts.remove("globex");
+        tenant_fonts
+            .register("initech", data(0))
+            .expect("font is registered");
+
+        let tenant_fonts = TenantFonts::new(
+            shared.book.clone(),
+            TenantFontLimits {
+                max_size: data(0).len() + data(1).len() - 1,
+                ..Default::default()
+            },
+        );
+        tenant_fonts
+            .register("acme", data(0))
+            .expect("font is registered");
+        assert!(too_large(tenant_fonts.register("acme", data(1))));
+        assert_eq!(
+            tenant_fonts
+                .get("acme")
+                .expect("tenant has fonts")
+                .fonts
+                .len(),
+            1
+        );
+    }
+}
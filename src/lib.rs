@@ -1,31 +1,36 @@
-use std::collections::HashMap;
-use std::io::Read;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 
-use tracing::instrument;
 use typst::Library;
-use typst::diag::{FileError, FileResult, PackageError, PackageResult, eco_format};
-use typst::foundations::{Bytes, Datetime};
+use typst::diag::{FileError, FileResult};
+use typst::foundations::{Bytes, Datetime, Dict};
 use typst::syntax::package::PackageSpec;
-use typst::syntax::{FileId, Source};
+use typst::syntax::{FileId, Source, VirtualPath};
 use typst::text::{Font, FontBook};
 use typst::utils::LazyHash;
-use typst_kit::fonts::{FontSearcher, FontSlot};
+use typst_kit::fonts::FontSlot;
 
-pub mod templates;
+use jiff::tz::TimeZone;
+use timezones::TimezoneConfig;
 
-/// This is the interface we have to implement such that `typst` can compile it.
-///
-/// I have tried to keep it as minimal as possible
-fn retry<T, E>(mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
-    if let Ok(ok) = f() { Ok(ok) } else { f() }
-}
+use fonts::{Coverage, FontConfig, FontEntry, FontFace, FontSet, TenantFontLimits, TenantFonts};
+use packages::{PackageConfig, PackageStorage};
 
-fn http_successful(status: u16) -> bool {
-    // 2XX
-    status / 100 == 2
+pub mod fonts;
+pub mod packages;
+pub mod render_pool;
+pub mod templates;
+#[cfg(any(test, feature = "test-registry"))]
+pub mod test_registry;
+pub mod timezones;
+
+/// Root directory of templates without a root of their own: `TEMPLATE_ROOT`, or `./templates`.
+pub fn default_root() -> PathBuf {
+    std::env::var_os("TEMPLATE_ROOT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("./templates"))
 }
 
 /// Cached template that holds expensive-to-initialize resources like fonts and library.
@@ -35,44 +40,59 @@ struct CachedWorldTemplate {
     book: LazyHash<FontBook>,
     fonts: Arc<Vec<FontSlot>>,
     root: PathBuf,
-    cache_directory: PathBuf,
-    http: ureq::Agent,
+    packages: Arc<PackageStorage>,
+    tenant_fonts: Arc<TenantFonts>,
+    timezones: TimezoneConfig,
 }
 
 impl CachedWorldTemplate {
     fn new() -> Self {
-        let fonts = FontSearcher::new().include_system_fonts(true).search();
+        let fonts = FontConfig::from_env().search();
         Self {
             library: LazyHash::new(Library::default()),
+            tenant_fonts: Arc::new(TenantFonts::new(
+                fonts.book.clone(),
+                TenantFontLimits::from_env(),
+            )),
             book: LazyHash::new(fonts.book),
             fonts: Arc::new(fonts.fonts),
-            root: PathBuf::from("./examples"),
-            cache_directory: std::env::var_os("CACHE_DIRECTORY")
-                .map(|os_path| os_path.into())
-                .unwrap_or(std::env::temp_dir()),
-            http: ureq::agent(),
+            root: default_root(),
+            packages: Arc::new(PackageStorage::new(PackageConfig::from_env())),
+            timezones: TimezoneConfig::from_env(),
         }
     }
 
+    /// Get or initialize the cached world template
+    fn get() -> &'static Self {
+        static CACHED_WORLD_TEMPLATE: OnceLock<CachedWorldTemplate> = OnceLock::new();
+        CACHED_WORLD_TEMPLATE.get_or_init(|| {
+            tracing::debug!("Initializing cached TypstWrapperWorld template");
+            CachedWorldTemplate::new()
+        })
+    }
+
     fn create_world_with_source(&self, source: String) -> TypstWrapperWorld {
         TypstWrapperWorld {
-            root: self.root.clone(),
+            root: Some(self.root.clone()),
             source: Source::detached(source),
             library: self.library.clone(),
             book: self.book.clone(),
             fonts: Arc::clone(&self.fonts),
             files: Arc::new(Mutex::new(HashMap::new())),
-            cache_directory: self.cache_directory.clone(),
-            http: self.http.clone(),
+            packages: Arc::clone(&self.packages),
+            retained_packages: Mutex::new(HashSet::new()),
+            overlay: HashMap::new(),
+            extra_fonts: Vec::new(),
             time: time::OffsetDateTime::now_utc(),
+            timezone: self.timezones.default.clone(),
         }
     }
 }
 
 /// Main interface that determines the environment for Typst.
 pub struct TypstWrapperWorld {
-    /// Root path to which files will be resolved.
-    root: PathBuf,
+    /// Root path to which files will be resolved, none to only resolve the overlay and packages.
+    root: Option<PathBuf>,
 
     /// The content of a source.
     source: Source,
@@ -89,32 +109,42 @@ pub struct TypstWrapperWorld {
     /// Map of all known files.
     files: Arc<Mutex<HashMap<FileId, FileEntry>>>,
 
-    /// Cache directory (e.g. where packages are downloaded to).
-    cache_directory: PathBuf,
+    /// Where packages are resolved and downloaded to.
+    packages: Arc<PackageStorage>,
+
+    /// Packages this world retained in `packages`, released when it is dropped.
+    retained_packages: Mutex<HashSet<PackageSpec>>,
 
-    /// http agent to download packages.
-    http: ureq::Agent,
+    /// Files supplied with the request, resolved before the root directory.
+    overlay: HashMap<VirtualPath, Bytes>,
 
-    /// Datetime.
+    /// Fonts supplied with the request, indexed after `fonts` in the book.
+    extra_fonts: Vec<Font>,
+
+    /// Time of the render, which `datetime.today()` is derived from.
     time: time::OffsetDateTime,
+
+    /// Zone of `datetime.today()` without an explicit offset, UTC if none.
+    timezone: Option<TimeZone>,
 }
 
 impl TypstWrapperWorld {
     pub fn new(root: String, source: String) -> Self {
         let root = PathBuf::from(root);
-        let fonts = FontSearcher::new().include_system_fonts(true).search();
+        let fonts = FontConfig::from_env().search();
 
         Self {
             library: LazyHash::new(Library::default()),
             book: LazyHash::new(fonts.book),
-            root,
+            root: Some(root),
             fonts: Arc::new(fonts.fonts),
             source: Source::detached(source),
             time: time::OffsetDateTime::now_utc(),
-            cache_directory: std::env::var_os("CACHE_DIRECTORY")
-                .map(|os_path| os_path.into())
-                .unwrap_or(std::env::temp_dir()),
-            http: ureq::agent(),
+            timezone: TimezoneConfig::from_env().default,
+            packages: Arc::new(PackageStorage::new(PackageConfig::from_env())),
+            retained_packages: Mutex::new(HashSet::new()),
+            overlay: HashMap::new(),
+            extra_fonts: Vec::new(),
             files: Arc::new(Mutex::new(HashMap::new())),
         }
     }
@@ -122,14 +152,130 @@ impl TypstWrapperWorld {
     /// Creates a new world with cached font/library data, updating only the source content.
     /// This avoids expensive font system search on every request.
     pub fn with_source(source: String) -> Self {
-        // Get or initialize the cached world template
-        static CACHED_WORLD_TEMPLATE: OnceLock<CachedWorldTemplate> = OnceLock::new();
-        let template = CACHED_WORLD_TEMPLATE.get_or_init(|| {
-            tracing::debug!("Initializing cached TypstWrapperWorld template");
-            CachedWorldTemplate::new()
-        });
+        CachedWorldTemplate::get().create_world_with_source(source)
+    }
+
+    /// The package storage shared by all worlds created through [`Self::with_source`].
+    pub fn shared_packages() -> Arc<PackageStorage> {
+        Arc::clone(&CachedWorldTemplate::get().packages)
+    }
+
+    /// The fonts registered by tenants, whose books extend the fonts of all worlds created through
+    /// [`Self::with_source`].
+    pub fn shared_tenant_fonts() -> Arc<TenantFonts> {
+        Arc::clone(&CachedWorldTemplate::get().tenant_fonts)
+    }
+
+    /// Makes the fonts of a tenant available, which must come from [`Self::shared_tenant_fonts`].
+    ///
+    /// This replaces fonts added through [`Self::with_fonts`], so it must be called before.
+    pub fn with_font_set(mut self, set: &FontSet) -> Self {
+        self.book = set.book.clone();
+        self.extra_fonts = set.fonts.clone();
+        self
+    }
+
+    /// Renders as if it was `time`, instead of the time the world was created.
+    pub fn with_time(mut self, time: time::OffsetDateTime) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Computes `datetime.today()` in `timezone`, e.g. `Asia/Kolkata`, unless the document asks
+    /// for an explicit offset.
+    pub fn with_timezone(mut self, timezone: TimeZone) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Makes the fonts registered by `tenant` available and uses its time zone, if configured.
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        if let Some(timezone) = CachedWorldTemplate::get().timezones.tenants.get(tenant) {
+            self.timezone = Some(timezone.clone());
+        }
+        match Self::shared_tenant_fonts().get(tenant) {
+            Some(set) => self.with_font_set(&set),
+            None => self,
+        }
+    }
+
+    /// Every font of this world, in the order of its font book.
+    pub fn font_entries(&self) -> Vec<FontEntry> {
+        (0..)
+            .map_while(|index| self.book.info(index))
+            .enumerate()
+            .map(|(index, info)| {
+                let path = self.fonts.get(index).and_then(FontSlot::path);
+                FontEntry {
+                    face: FontFace::of(info),
+                    path: path.map(ToOwned::to_owned),
+                    embedded: index < self.fonts.len() && path.is_none(),
+                }
+            })
+            .collect()
+    }
+
+    /// Checks whether the fonts of `family` in this world can display every character of `text`.
+    pub fn font_coverage(&self, family: &str, text: &str) -> Coverage {
+        fonts::coverage(&self.book, family, text)
+    }
+
+    /// Resolves local files such as `#image("logo.png")` relative to `root` instead of the default.
+    ///
+    /// Files outside of `root` remain inaccessible.
+    pub fn with_root(mut self, root: impl AsRef<Path>) -> Self {
+        self.root = Some(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Resolves packages from `packages` instead of the storage shared by all worlds.
+    pub fn with_packages(mut self, packages: Arc<PackageStorage>) -> Self {
+        self.packages = packages;
+        self
+    }
 
-        template.create_world_with_source(source)
+    /// Never reads local files from disk, so that only the overlay and packages are accessible.
+    pub fn isolated(mut self) -> Self {
+        self.root = None;
+        self
+    }
+
+    /// Places the main source at `path` in the project (`main.typ` by default), so that its
+    /// relative imports resolve next to it.
+    pub fn with_main_path(mut self, path: &str) -> Self {
+        let id = FileId::new(None, VirtualPath::new(path));
+        self.source = Source::new(id, self.source.text().to_owned());
+        self
+    }
+
+    /// Makes `fonts` available in addition to the system fonts.
+    pub fn with_fonts(mut self, fonts: impl IntoIterator<Item = Font>) -> Self {
+        let mut book = FontBook::clone(&self.book);
+        for font in fonts {
+            book.push(font.info().clone());
+            self.extra_fonts.push(font);
+        }
+        self.book = LazyHash::new(book);
+        self
+    }
+
+    /// Mounts in-memory `files`, keyed by their path in the project (e.g. `logo.png`), over the
+    /// root directory.
+    pub fn with_files(mut self, files: impl IntoIterator<Item = (String, Vec<u8>)>) -> Self {
+        self.overlay.extend(
+            files
+                .into_iter()
+                .map(|(path, content)| (VirtualPath::new(path), Bytes::new(content))),
+        );
+        self
+    }
+
+    /// Exposes `inputs` to the document as `sys.inputs`.
+    ///
+    /// This rebuilds the standard library, so it should only be used when there are inputs.
+    pub fn with_inputs(mut self, inputs: Dict) -> Self {
+        self.library = LazyHash::new(Library::builder().with_inputs(inputs).build());
+        self
     }
 }
 
@@ -148,6 +294,13 @@ impl FileEntry {
         }
     }
 
+    fn from_bytes(bytes: Bytes) -> Self {
+        Self {
+            bytes,
+            source: None,
+        }
+    }
+
     fn source(&mut self, id: FileId) -> FileResult<Source> {
         let source = if let Some(source) = &self.source {
             source
@@ -164,80 +317,55 @@ impl FileEntry {
 impl TypstWrapperWorld {
     /// Helper to handle file requests.
     ///
-    /// Requests will be either in packages or a local file.
+    /// Requests will be either in packages, in the overlay or a local file.
     fn file(&self, id: FileId) -> FileResult<FileEntry> {
         let mut files = self.files.lock().map_err(|_| FileError::AccessDenied)?;
         if let Some(entry) = files.get(&id) {
             return Ok(entry.clone());
         }
+        if id.package().is_none()
+            && let Some(bytes) = self.overlay.get(id.vpath())
+        {
+            return Ok(files
+                .entry(id)
+                .or_insert(FileEntry::from_bytes(bytes.clone()))
+                .clone());
+        }
         let path = if let Some(package) = id.package() {
-            // Fetching file from package
-            let package_dir = self.download_package(package)?;
+            // Fetching file from package, which must not be evicted while this world may read it
+            if self
+                .retained_packages
+                .lock()
+                .map_err(|_| FileError::AccessDenied)?
+                .insert(package.clone())
+            {
+                self.packages.retain(package);
+            }
+            let package_dir = self.packages.prepare_package(package)?;
             id.vpath().resolve(&package_dir)
         } else {
             // Fetching file from disk
-            id.vpath().resolve(&self.root)
+            let root = self
+                .root
+                .as_ref()
+                .ok_or_else(|| FileError::NotFound(id.vpath().as_rootless_path().to_path_buf()))?;
+            id.vpath().resolve(root)
         }
         .ok_or(FileError::AccessDenied)?;
 
-        let content = std::fs::read(&path).map_err(|error| FileError::from_io(error, &path))?;
+        let content = std::fs::read(&path).map_err(|error| match error.kind() {
+            // Report the path as written in the document rather than where it was searched on
+            // the server.
+            std::io::ErrorKind::NotFound => {
+                FileError::NotFound(id.vpath().as_rootless_path().to_path_buf())
+            }
+            _ => FileError::from_io(error, &path),
+        })?;
         Ok(files
             .entry(id)
             .or_insert(FileEntry::new(content, None))
             .clone())
     }
-
-    /// Downloads the package and returns the system path of the unpacked package.
-    #[instrument(skip(self, package))]
-    fn download_package(&self, package: &PackageSpec) -> PackageResult<PathBuf> {
-        let package_subdir = format!("{}/{}/{}", package.namespace, package.name, package.version);
-        let path = self.cache_directory.join(package_subdir);
-
-        if path.exists() {
-            return Ok(path);
-        }
-
-        eprintln!("downloading {package}");
-        let url = format!(
-            "https://packages.typst.org/{}/{}-{}.tar.gz",
-            package.namespace, package.name, package.version,
-        );
-
-        let mut response = retry(|| {
-            let response = self
-                .http
-                .get(&url)
-                .call()
-                .map_err(|error| eco_format!("{error}"))?;
-
-            let status = response.status();
-            if !http_successful(status.as_u16()) {
-                return Err(eco_format!(
-                    "response returned unsuccessful status code {status}",
-                ));
-            }
-
-            Ok(response)
-        })
-        .map_err(|error| PackageError::NetworkFailed(Some(error)))?;
-
-        let mut compressed_archive = Vec::new();
-        response
-            .body_mut()
-            .as_reader()
-            .read_to_end(&mut compressed_archive)
-            .map_err(|error| PackageError::NetworkFailed(Some(eco_format!("{error}"))))?;
-        let raw_archive = zune_inflate::DeflateDecoder::new(&compressed_archive)
-            .decode_gzip()
-            .map_err(|error| PackageError::MalformedArchive(Some(eco_format!("{error}"))))?;
-        let mut archive = tar::Archive::new(raw_archive.as_slice());
-        archive.unpack(&path).map_err(|error| {
-            _ = std::fs::remove_dir_all(&path);
-            PackageError::MalformedArchive(Some(eco_format!("{error}")))
-        })?;
-
-        Ok(path)
-    }
 }
 
 /// This is the interface we have to implement such that `typst` can compile it.
@@ -275,16 +403,50 @@ impl typst::World for TypstWrapperWorld {
 
     /// Accessing a specified font per index of font book.
     fn font(&self, id: usize) -> Option<Font> {
-        self.fonts[id].get()
+        match self.fonts.get(id) {
+            Some(slot) => slot.get(),
+            None => self.extra_fonts.get(id - self.fonts.len()).cloned(),
+        }
     }
 
     /// Get the current date.
     ///
-    /// Optionally, an offset in hours is given.
+    /// Optionally, an offset in hours from UTC is given. Otherwise, the date is taken in the
+    /// world's time zone, which may also be offset by fractions of an hour, or without one at the
+    /// offset of the world's time, like the creation date of a PDF rendered at that time.
     fn today(&self, offset: Option<i64>) -> Option<Datetime> {
-        let offset = offset.unwrap_or(0);
-        let offset = time::UtcOffset::from_hms(offset.try_into().ok()?, 0, 0).ok()?;
-        let time = self.time.checked_to_offset(offset)?;
-        Some(Datetime::Date(time.date()))
+        let utc = self.time.to_offset(time::UtcOffset::UTC);
+        let date = match (offset, &self.timezone) {
+            // Added as a duration, since `UtcOffset` is limited to less than 26 hours.
+            (Some(hours), _) => utc
+                .checked_add(time::Duration::seconds(hours.checked_mul(3600)?))?
+                .date(),
+            (None, Some(timezone)) => {
+                let timestamp =
+                    jiff::Timestamp::new(utc.unix_timestamp(), utc.nanosecond().try_into().ok()?)
+                        .ok()?;
+                let date = timestamp.to_zoned(timezone.clone()).date();
+                time::Date::from_calendar_date(
+                    date.year().into(),
+                    u8::try_from(date.month()).ok()?.try_into().ok()?,
+                    u8::try_from(date.day()).ok()?,
+                )
+                .ok()?
+            }
+            (None, None) => self.time.date(),
+        };
+        Some(Datetime::Date(date))
+    }
+}
+
+impl Drop for TypstWrapperWorld {
+    fn drop(&mut self) {
+        let retained = self
+            .retained_packages
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for package in retained.drain() {
+            self.packages.release(&package);
+        }
     }
 }
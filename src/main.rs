@@ -1,21 +1,156 @@
-use axum::{Router, routing::get};
-use tracing::info;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post},
+};
+use tracing::{error, info};
+use typst_pdf_api::TypstWrapperWorld;
+use typst_pdf_api::packages::PinnedPackages;
+use typst_pdf_api::render_pool::{RenderPool, RenderPoolConfig};
+use typst_pdf_api::templates::registry::TemplateRegistry;
+
+mod cli;
 mod routes;
 
-use routes::pdf_generation_controller;
+use routes::{
+    AppState, UploadLimits, bundle_controller, font_coverage_controller, german_invoice_controller,
+    list_cached_packages_controller, list_fonts_controller, list_templates_controller,
+    list_tenant_fonts_controller, pdf_generation_controller, purge_cached_package_controller,
+    readiness_controller, register_tenant_font_controller, remove_tenant_fonts_controller,
+    upload_controller,
+};
 
-#[tokio::main]
-async fn main() {
+fn main() -> ExitCode {
     // Setup tracing subscriber for logging
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
-    // build our application with a single route
+    // build our application
     // let world = Arc::new(TypstWrapperWorld::new("examples".to_owned()));
+    let registry = TemplateRegistry::with_builtin_templates();
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => {
+            serve(Arc::new(registry));
+            ExitCode::SUCCESS
+        }
+        Some("serve") => match args.collect::<Vec<_>>().as_slice() {
+            [] => {
+                serve(Arc::new(registry));
+                ExitCode::SUCCESS
+            }
+            // Overrides `TEMPLATE_ROOT` for templates without a root of their own.
+            [flag, root] if flag == "--root" => {
+                serve(Arc::new(registry.with_root(root)));
+                ExitCode::SUCCESS
+            }
+            _ => {
+                eprintln!("Expected `serve [--root PATH]`");
+                ExitCode::FAILURE
+            }
+        },
+        Some("lock") => cli::lock(&registry, args.next()),
+        Some("cache") => cli::cache(&registry, args.collect()),
+        Some("fonts") => cli::fonts(args.collect()),
+        Some(command) => {
+            eprintln!(
+                "Unknown command `{command}`, expected `serve [--root PATH]`, `lock [PATH]`, `cache` or `fonts`"
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Resolves the pinned packages every [`PINNED_PACKAGES_RETRY`] until all of them are available.
+async fn retry_pinned_packages(pinned_packages: Arc<PinnedPackages>) {
+    loop {
+        tokio::time::sleep(PINNED_PACKAGES_RETRY).await;
+        let pinned_packages = Arc::clone(&pinned_packages);
+        let failures = tokio::task::spawn_blocking(move || pinned_packages.resolve())
+            .await
+            .expect("Failed to resolve pinned packages");
+        if failures.is_empty() {
+            info!("Pinned packages resolved, ready");
+            return;
+        }
+        error!("Not ready, pinned packages are unavailable: {:?}", failures);
+    }
+}
+
+/// Delay between attempts to resolve the pinned packages that failed on startup.
+const PINNED_PACKAGES_RETRY: Duration = Duration::from_secs(30);
+
+#[tokio::main]
+async fn serve(registry: Arc<TemplateRegistry>) {
+    // Resolve the packages of all templates before accepting traffic, so that no request pays for
+    // a download.
+    let pinned_packages = Arc::new(PinnedPackages::new(
+        registry.packages(),
+        TypstWrapperWorld::shared_packages(),
+    ));
+    info!(
+        "Resolving {} pinned package(s)",
+        pinned_packages.packages().len()
+    );
+    let failures = tokio::task::spawn_blocking({
+        let pinned_packages = Arc::clone(&pinned_packages);
+        move || pinned_packages.resolve()
+    })
+    .await
+    .expect("Failed to resolve pinned packages");
+    if !failures.is_empty() {
+        error!("Not ready, pinned packages are unavailable: {:?}", failures);
+        // `/ready` only looks at the cache, so keep retrying the downloads in the background.
+        tokio::spawn(retry_pinned_packages(Arc::clone(&pinned_packages)));
+    }
+
+    let render_pool_config = RenderPoolConfig::from_env();
+    info!("Render pool: {:?}", render_pool_config);
+    let upload_limits = UploadLimits::from_env();
+    info!("Upload limits: {:?}", upload_limits);
+    let state = AppState {
+        registry,
+        render_pool: Arc::new(RenderPool::new(render_pool_config)),
+        pinned_packages,
+        package_storage: TypstWrapperWorld::shared_packages(),
+        tenant_fonts: TypstWrapperWorld::shared_tenant_fonts(),
+        upload_limits,
+    };
 
-    let app = Router::new().route("/", get(pdf_generation_controller));
+    let app = Router::new()
+        .route("/", get(pdf_generation_controller))
+        .route("/templates", get(list_templates_controller))
+        .route("/invoices/german", post(german_invoice_controller))
+        .route(
+            "/render",
+            post(upload_controller).layer(DefaultBodyLimit::max(upload_limits.max_total_size)),
+        )
+        .route(
+            "/render/bundle",
+            post(bundle_controller).layer(DefaultBodyLimit::max(upload_limits.max_total_size)),
+        )
+        .route("/ready", get(readiness_controller))
+        .route("/packages/cache", get(list_cached_packages_controller))
+        .route(
+            "/packages/cache/{namespace}/{name}/{version}",
+            delete(purge_cached_package_controller),
+        )
+        .route("/fonts", get(list_fonts_controller))
+        .route("/fonts/coverage", get(font_coverage_controller))
+        .route(
+            "/tenants/{tenant}/fonts",
+            get(list_tenant_fonts_controller)
+                .post(register_tenant_font_controller)
+                .delete(remove_tenant_fonts_controller)
+                .layer(DefaultBodyLimit::max(upload_limits.max_part_size)),
+        )
+        .with_state(state);
 
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
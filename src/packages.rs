@@ -0,0 +1,1511 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use tracing::instrument;
+use typst::diag::{EcoString, PackageError, PackageResult, eco_format};
+use typst::syntax::package::PackageSpec;
+use typst::syntax::{SyntaxNode, ast};
+
+fn retry<T, E>(mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    if let Ok(ok) = f() { Ok(ok) } else { f() }
+}
+
+fn http_successful(status: u16) -> bool {
+    // 2XX
+    status / 100 == 2
+}
+
+/// Registry serving the `@preview` namespace unless configured otherwise.
+pub const DEFAULT_REGISTRY_URL: &str = "https://packages.typst.org";
+
+/// Where packages are looked up and whether they may be downloaded.
+#[derive(Debug, Clone)]
+pub struct PackageConfig {
+    /// Local packages, searched before the cache and the network.
+    /// Laid out as `{namespace}/{name}/{version}`, like the cache directory.
+    pub package_path: Option<PathBuf>,
+
+    /// Cache directory (e.g. where packages are downloaded to).
+    pub cache_directory: PathBuf,
+
+    /// Never access the network, packages must be in the package path or the cache.
+    pub offline: bool,
+
+    /// Base URL of the registry serving `@preview` packages.
+    pub registry_url: String,
+
+    /// Base URLs of registries serving other namespaces, e.g. `acme` for `@acme/letterhead:1.0.0`.
+    /// Packages of namespaces that are not listed are never downloaded.
+    pub namespace_registries: HashMap<String, String>,
+
+    /// [`Lockfile`] that downloaded archives are verified against.
+    pub lockfile: Option<PathBuf>,
+
+    /// Upper bound for the total size of the cache in bytes, enforced after every download by
+    /// evicting the least recently used packages. Pinned packages are never evicted.
+    pub max_cache_size: Option<u64>,
+
+    /// Bounds on what a downloaded archive may unpack to.
+    pub extraction_limits: ExtractionLimits,
+
+    /// Maximum time to establish a connection to a registry.
+    pub connect_timeout: Duration,
+
+    /// Maximum time for a whole download, including the response body.
+    pub download_timeout: Duration,
+}
+
+impl Default for PackageConfig {
+    fn default() -> Self {
+        Self {
+            package_path: None,
+            cache_directory: std::env::temp_dir().join("typst-packages"),
+            offline: false,
+            registry_url: DEFAULT_REGISTRY_URL.to_owned(),
+            namespace_registries: HashMap::new(),
+            lockfile: None,
+            max_cache_size: None,
+            extraction_limits: ExtractionLimits::default(),
+            connect_timeout: Duration::from_secs(10),
+            download_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+impl PackageConfig {
+    /// Reads `PACKAGE_PATH`, `CACHE_DIRECTORY`, `PACKAGES_OFFLINE`, `PACKAGE_REGISTRY_URL`,
+    /// `PACKAGE_NAMESPACE_REGISTRIES`, `PACKAGE_LOCKFILE`, `PACKAGE_CACHE_MAX_SIZE` (in bytes),
+    /// `PACKAGE_MAX_DECOMPRESSED_SIZE` (in bytes), `PACKAGE_MAX_ENTRIES`,
+    /// `PACKAGE_CONNECT_TIMEOUT_SECS` and `PACKAGE_DOWNLOAD_TIMEOUT_SECS`.
+    ///
+    /// The cache directory defaults to the system temp dir. Offline mode is enabled by `1` or `true`.
+    /// Namespace registries are given as `namespace=url` pairs separated by commas.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            package_path: std::env::var_os("PACKAGE_PATH").map(|os_path| os_path.into()),
+            cache_directory: std::env::var_os("CACHE_DIRECTORY")
+                .map(|os_path| os_path.into())
+                .unwrap_or(default.cache_directory),
+            offline: std::env::var("PACKAGES_OFFLINE")
+                .is_ok_and(|value| matches!(value.as_str(), "1" | "true")),
+            registry_url: std::env::var("PACKAGE_REGISTRY_URL").unwrap_or(default.registry_url),
+            namespace_registries: std::env::var("PACKAGE_NAMESPACE_REGISTRIES")
+                .map(|value| parse_namespace_registries(&value))
+                .unwrap_or_default(),
+            lockfile: std::env::var_os("PACKAGE_LOCKFILE").map(|os_path| os_path.into()),
+            max_cache_size: std::env::var("PACKAGE_CACHE_MAX_SIZE")
+                .ok()
+                .and_then(|value| value.parse().ok()),
+            extraction_limits: ExtractionLimits {
+                max_decompressed_size: std::env::var("PACKAGE_MAX_DECOMPRESSED_SIZE")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default.extraction_limits.max_decompressed_size),
+                max_entries: std::env::var("PACKAGE_MAX_ENTRIES")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default.extraction_limits.max_entries),
+            },
+            connect_timeout: std::env::var("PACKAGE_CONNECT_TIMEOUT_SECS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map_or(default.connect_timeout, Duration::from_secs),
+            download_timeout: std::env::var("PACKAGE_DOWNLOAD_TIMEOUT_SECS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map_or(default.download_timeout, Duration::from_secs),
+        }
+    }
+
+    /// Base URL of the registry serving `namespace`, if any.
+    fn registry_url(&self, namespace: &str) -> Option<&str> {
+        match self.namespace_registries.get(namespace) {
+            Some(url) => Some(url),
+            None if namespace == "preview" => Some(&self.registry_url),
+            None => None,
+        }
+    }
+}
+
+/// Bounds on what a downloaded package archive may unpack to, as archives come from the network.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractionLimits {
+    /// Maximum size of the decompressed archive in bytes.
+    pub max_decompressed_size: usize,
+    /// Maximum number of files and directories in the archive.
+    pub max_entries: usize,
+}
+
+impl Default for ExtractionLimits {
+    fn default() -> Self {
+        Self {
+            max_decompressed_size: 64 * 1024 * 1024,
+            max_entries: 10_000,
+        }
+    }
+}
+
+fn parse_namespace_registries(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let (namespace, url) = pair.split_once('=')?;
+            Some((namespace.trim().to_owned(), url.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// SHA-256 hashes of package archives, keyed by package spec (e.g. `@preview/example:0.1.0`).
+///
+/// Stored as JSON. Downloaded archives of listed packages must match their hash, so that a
+/// re-published package cannot silently change the output, and unlisted packages are not
+/// downloaded at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lockfile {
+    packages: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read(path)?;
+        serde_json::from_slice(&content).map_err(std::io::Error::other)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut content = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+        content.push('\n');
+        std::fs::write(path, content)
+    }
+
+    /// The locked hash of `package`, as lowercase hex.
+    pub fn hash(&self, package: &PackageSpec) -> Option<&str> {
+        self.packages.get(&package.to_string()).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, package: &PackageSpec, hash: String) {
+        self.packages.insert(package.to_string(), hash);
+    }
+}
+
+/// A downloaded package archive, see [`PackageStorage::inspect_archive`].
+#[derive(Debug, Clone)]
+pub struct ArchiveInfo {
+    /// SHA-256 of the archive as lowercase hex, as stored in a [`Lockfile`].
+    pub hash: String,
+    /// Packages imported by the sources of the package, which must be locked as well.
+    pub imports: Vec<PackageSpec>,
+}
+
+/// SHA-256 of a package archive as lowercase hex, as stored in a [`Lockfile`].
+pub fn archive_hash(archive: &[u8]) -> String {
+    to_hex(&Sha256::digest(archive))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Resolves packages to directories on disk, downloading them if needed.
+#[derive(Debug)]
+pub struct PackageStorage {
+    config: PackageConfig,
+
+    /// Hashes that downloaded archives must match, read from [`PackageConfig::lockfile`].
+    lockfile: Option<Lockfile>,
+
+    /// http agent to download packages.
+    http: ureq::Agent,
+
+    /// One lock per package, held while it is being downloaded or removed.
+    download_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+
+    /// Packages that are never evicted from the cache.
+    pinned: Mutex<HashSet<PackageSpec>>,
+
+    /// Packages retained by renders that may still read their files, with the number of renders.
+    /// Held while evicting, so that a package cannot be retained while it is being removed.
+    in_use: Mutex<HashMap<PackageSpec, usize>>,
+}
+
+impl PackageStorage {
+    /// # Panics
+    ///
+    /// If a lockfile is configured but cannot be read, rather than skipping the verification.
+    pub fn new(config: PackageConfig) -> Self {
+        let lockfile = config.lockfile.as_deref().map(|path| {
+            Lockfile::load(path).unwrap_or_else(|error| {
+                panic!(
+                    "Failed to read package lockfile {}: {error}",
+                    path.display()
+                )
+            })
+        });
+
+        let http = ureq::Agent::config_builder()
+            .timeout_connect(Some(config.connect_timeout))
+            .timeout_global(Some(config.download_timeout))
+            .build()
+            .new_agent();
+
+        Self {
+            config,
+            lockfile,
+            http,
+            download_locks: Mutex::new(HashMap::new()),
+            pinned: Mutex::new(HashSet::new()),
+            in_use: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the system path of the unpacked package, downloading it into the cache if it is
+    /// neither in the package path nor already cached.
+    pub fn prepare_package(&self, package: &PackageSpec) -> PackageResult<PathBuf> {
+        let package_subdir = package_subdir(package);
+
+        if let Some(package_path) = &self.config.package_path {
+            let path = package_path.join(&package_subdir);
+            if path.exists() {
+                return Ok(path);
+            }
+        }
+
+        let path = self.config.cache_directory.join(&package_subdir);
+        if path.exists() {
+            mark_used(&path);
+            return Ok(path);
+        }
+
+        if self.config.offline {
+            return Err(PackageError::Other(Some(eco_format!(
+                "{package} is not available locally and package downloads are disabled (offline mode)"
+            ))));
+        }
+
+        // Only one download per package, across threads and processes. Whoever waited for the
+        // locks finds the package in the cache, so concurrent requests share the first download.
+        {
+            let download_lock = self.download_lock(&package_subdir);
+            let _download_guard = download_lock.lock().unwrap_or_else(PoisonError::into_inner);
+            let _lock_file = lock_file(package, &path).map_err(|error| {
+                PackageError::Other(Some(eco_format!("failed to lock {package}: {error}")))
+            })?;
+            if path.exists() {
+                return Ok(path);
+            }
+
+            self.download_package(package, &path)?;
+        }
+
+        if let Err(error) = self.enforce_cache_limit(Some(package)) {
+            tracing::warn!("Failed to enforce the package cache limit: {error}");
+        }
+        Ok(path)
+    }
+
+    /// Whether `package` is in the package path or the cache, without downloading it.
+    pub fn is_available_locally(&self, package: &PackageSpec) -> bool {
+        let package_subdir = package_subdir(package);
+        self.config
+            .package_path
+            .iter()
+            .chain([&self.config.cache_directory])
+            .any(|dir| dir.join(&package_subdir).exists())
+    }
+
+    fn download_lock(&self, package_subdir: &str) -> Arc<Mutex<()>> {
+        let mut locks = self
+            .download_locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(locks.entry(package_subdir.to_owned()).or_default())
+    }
+
+    /// Protects `packages` from eviction.
+    pub fn pin(&self, packages: &[PackageSpec]) {
+        self.pinned
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(packages.iter().cloned());
+    }
+
+    /// Protects `package` from eviction until it is [released](Self::release) as often, e.g. while
+    /// a render may still read its files.
+    pub fn retain(&self, package: &PackageSpec) {
+        *self
+            .in_use
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(package.clone())
+            .or_default() += 1;
+    }
+
+    /// Undoes one [`Self::retain`] of `package`.
+    pub fn release(&self, package: &PackageSpec) {
+        let mut in_use = self.in_use.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = in_use.get_mut(package) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(package);
+            }
+        }
+    }
+
+    /// Packages in the cache directory, least recently used first.
+    pub fn cached_packages(&self) -> std::io::Result<Vec<CachedPackage>> {
+        let cache_directory = &self.config.cache_directory;
+        let mut packages = Vec::new();
+        // Other files may end up in the cache directory, so only directories laid out like a
+        // package and holding a manifest are considered, and everything else is never removed.
+        for namespace in subdirectories(cache_directory)? {
+            let namespace_dir = cache_directory.join(&namespace);
+            for name in subdirectories(&namespace_dir).unwrap_or_default() {
+                let name_dir = namespace_dir.join(&name);
+                for version in subdirectories(&name_dir).unwrap_or_default() {
+                    let Ok(package) = format!("@{namespace}/{name}:{version}").parse() else {
+                        continue;
+                    };
+                    let path = name_dir.join(&version);
+                    if !path.join("typst.toml").is_file() {
+                        continue;
+                    }
+                    packages.push(CachedPackage {
+                        package,
+                        size: directory_size(&path)?,
+                        last_used: std::fs::metadata(&path)?.modified()?.into(),
+                    });
+                }
+            }
+        }
+
+        packages.sort_by_key(|package| package.last_used);
+        Ok(packages)
+    }
+
+    /// Removes `package` from the cache, returning whether it was cached.
+    ///
+    /// Waits for a running download of the package to finish first.
+    pub fn purge(&self, package: &PackageSpec) -> std::io::Result<bool> {
+        let package_subdir = package_subdir(package);
+        let path = self.config.cache_directory.join(&package_subdir);
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        let download_lock = self.download_lock(&package_subdir);
+        let _download_guard = download_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let _lock_file = lock_file(package, &path)?;
+        match std::fs::remove_dir_all(&path) {
+            Ok(()) => Ok(true),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Evicts the least recently used packages until the cache fits into
+    /// [`PackageConfig::max_cache_size`], and returns them.
+    ///
+    /// Pinned and [retained](Self::retain) packages and `keep` are never evicted, even if the
+    /// cache stays too large.
+    pub fn enforce_cache_limit(
+        &self,
+        keep: Option<&PackageSpec>,
+    ) -> std::io::Result<Vec<CachedPackage>> {
+        let Some(max_cache_size) = self.config.max_cache_size else {
+            return Ok(Vec::new());
+        };
+
+        let cached = self.cached_packages()?;
+        let mut size: u64 = cached.iter().map(|cached| cached.size).sum();
+        let pinned = self
+            .pinned
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+
+        let in_use = self.in_use.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut evicted = Vec::new();
+        for cached in cached {
+            if size <= max_cache_size {
+                break;
+            }
+            if pinned.contains(&cached.package)
+                || in_use.contains_key(&cached.package)
+                || keep == Some(&cached.package)
+            {
+                continue;
+            }
+
+            if self.purge(&cached.package)? {
+                tracing::info!("Evicted {} ({} bytes)", cached.package, cached.size);
+                size = size.saturating_sub(cached.size);
+                evicted.push(cached);
+            }
+        }
+
+        Ok(evicted)
+    }
+
+    /// Downloads the archive of `package` and returns its hash and the packages it imports,
+    /// without verifying it or adding it to the cache.
+    ///
+    /// Used to generate the [`Lockfile`].
+    pub fn inspect_archive(&self, package: &PackageSpec) -> PackageResult<ArchiveInfo> {
+        let unpack_dir = tempfile::tempdir()
+            .map_err(|error| PackageError::Other(Some(eco_format!("{error}"))))?;
+        let hash = self.download_to(package, unpack_dir.path())?;
+        let imports = package_imports(unpack_dir.path())
+            .into_iter()
+            .filter(|import| import != package)
+            .collect();
+        Ok(ArchiveInfo { hash, imports })
+    }
+
+    /// Downloads the package and unpacks it to `path`.
+    ///
+    /// It is unpacked next to `path` first and only renamed into place once complete and verified,
+    /// so that `path` only ever exists with the complete package.
+    #[instrument(skip(self, package))]
+    fn download_package(&self, package: &PackageSpec, path: &Path) -> PackageResult<()> {
+        let expected = match &self.lockfile {
+            Some(lockfile) => Some(lockfile.hash(package).ok_or_else(|| {
+                PackageError::Other(Some(eco_format!(
+                    "{package} is not listed in the package lockfile"
+                )))
+            })?),
+            None => None,
+        };
+
+        // Removed on drop, unless it was renamed into place.
+        let unpack_dir = tempfile::Builder::new()
+            .prefix(".download-")
+            .tempdir_in(path.parent().unwrap_or(path))
+            .map_err(|error| PackageError::Other(Some(eco_format!("{error}"))))?;
+
+        let actual = self.download_to(package, unpack_dir.path())?;
+        if let Some(expected) = expected
+            && actual != expected
+        {
+            return Err(PackageError::MalformedArchive(Some(eco_format!(
+                "archive of {package} does not match the lockfile (expected sha256 {expected}, got {actual})"
+            ))));
+        }
+
+        std::fs::rename(unpack_dir.path(), path)
+            .map_err(|error| PackageError::Other(Some(eco_format!("{error}"))))?;
+
+        Ok(())
+    }
+
+    /// Downloads the package, unpacks it into the empty directory `dir` and returns the hash of
+    /// its archive.
+    ///
+    /// The archive is streamed from the registry through the gzip decoder into the unpacked files,
+    /// so memory use does not depend on its size.
+    fn download_to(&self, package: &PackageSpec, dir: &Path) -> PackageResult<String> {
+        let mut response = self.request_archive(package)?;
+        let total = response.body().content_length();
+        let mut download = DownloadReader::new(response.body_mut().as_reader(), package, total);
+
+        let limits = self.config.extraction_limits;
+        let mut decompressed = LimitedReader::new(
+            flate2::read::GzDecoder::new(&mut download),
+            limits.max_decompressed_size,
+        );
+        let unpacked = unpack_archive(&mut decompressed, dir, &limits).and_then(|()| {
+            // Decompress the rest, e.g. the padding after the end of the tar, so that the gzip
+            // checksum is verified.
+            std::io::copy(&mut decompressed, &mut std::io::sink())
+                .map(drop)
+                .map_err(|error| eco_format!("{error}"))
+        });
+        let exceeded = decompressed.exceeded();
+        drop(decompressed);
+
+        if let Some(error) = download.network_error.take() {
+            return Err(PackageError::NetworkFailed(Some(error)));
+        }
+        if exceeded {
+            return Err(PackageError::MalformedArchive(Some(eco_format!(
+                "archive of {package} exceeds {} bytes when decompressed",
+                limits.max_decompressed_size
+            ))));
+        }
+        unpacked.map_err(|error| {
+            PackageError::MalformedArchive(Some(eco_format!("archive of {package}: {error}")))
+        })?;
+
+        // Hash anything the gzip decoder did not need as well.
+        std::io::copy(&mut download, &mut std::io::sink())
+            .map_err(|error| PackageError::NetworkFailed(Some(eco_format!("{error}"))))?;
+        Ok(download.finish())
+    }
+
+    /// Requests the compressed archive of `package` from the registry of its namespace.
+    fn request_archive(
+        &self,
+        package: &PackageSpec,
+    ) -> PackageResult<ureq::http::Response<ureq::Body>> {
+        let Some(registry_url) = self.config.registry_url(&package.namespace) else {
+            return Err(PackageError::NotFound(package.clone()));
+        };
+
+        let url = format!(
+            "{}/{}/{}-{}.tar.gz",
+            registry_url.trim_end_matches('/'),
+            package.namespace,
+            package.name,
+            package.version,
+        );
+
+        retry(|| {
+            let response = self
+                .http
+                .get(&url)
+                .call()
+                .map_err(|error| eco_format!("{error}"))?;
+
+            let status = response.status();
+            if !http_successful(status.as_u16()) {
+                return Err(eco_format!(
+                    "response returned unsuccessful status code {status}",
+                ));
+            }
+
+            Ok(response)
+        })
+        .map_err(|error| PackageError::NetworkFailed(Some(error)))
+    }
+}
+
+/// Bytes between two progress events of a download.
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
+/// Hashes a downloaded archive while it is read and reports the progress as `tracing` events.
+struct DownloadReader<R> {
+    inner: R,
+    package: String,
+    hasher: Sha256,
+    downloaded: u64,
+    total: Option<u64>,
+    next_progress: u64,
+    /// The reason the download broke off, to tell it apart from a malformed archive.
+    network_error: Option<EcoString>,
+}
+
+impl<R: Read> DownloadReader<R> {
+    fn new(inner: R, package: &PackageSpec, total: Option<u64>) -> Self {
+        tracing::info!(package = %package, total, "Downloading package");
+        Self {
+            inner,
+            package: package.to_string(),
+            hasher: Sha256::new(),
+            downloaded: 0,
+            total,
+            next_progress: PROGRESS_INTERVAL,
+            network_error: None,
+        }
+    }
+
+    /// Returns the hash of everything read so far.
+    fn finish(self) -> String {
+        tracing::info!(
+            package = %self.package,
+            downloaded = self.downloaded,
+            "Downloaded package"
+        );
+        to_hex(&self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for DownloadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf).inspect_err(|error| {
+            self.network_error = Some(eco_format!("{error}"));
+        })?;
+
+        self.hasher.update(&buf[..read]);
+        self.downloaded += read as u64;
+        if self.downloaded >= self.next_progress {
+            self.next_progress = self.downloaded + PROGRESS_INTERVAL;
+            tracing::debug!(
+                package = %self.package,
+                downloaded = self.downloaded,
+                total = self.total,
+                "Downloading package"
+            );
+        }
+        Ok(read)
+    }
+}
+
+/// Fails reads once more than `limit` bytes were read, e.g. to stop gzip bombs.
+pub(crate) struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+    exceeded: bool,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub(crate) fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    pub(crate) fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Read one byte past the limit to tell an archive of exactly `limit` bytes from a larger one.
+        let len = buf.len().min(self.remaining.saturating_add(1));
+        let read = self.inner.read(&mut buf[..len])?;
+        if read > self.remaining {
+            self.exceeded = true;
+            return Err(std::io::Error::other("decompressed size limit exceeded"));
+        }
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+/// Unpacks a package archive into `dir`.
+///
+/// Unlike [`tar::Archive::unpack`], this rejects links, paths outside of `dir` and anything besides
+/// regular files and directories, and enforces [`ExtractionLimits::max_entries`].
+fn unpack_archive(
+    archive: impl Read,
+    dir: &Path,
+    limits: &ExtractionLimits,
+) -> Result<(), EcoString> {
+    visit_tar_entries(archive, limits, "package directory", |path, mut entry| {
+        entry
+            .unpack_in(dir)
+            .map(drop)
+            .map_err(|error| eco_format!("failed to unpack {}: {error}", path.display()))
+    })
+}
+
+/// Checks every entry of a tar archive as described in [`unpack_archive`] before passing it to
+/// `visit`, with the path it would be unpacked at.
+pub(crate) fn visit_tar_entries<R: Read>(
+    archive: R,
+    limits: &ExtractionLimits,
+    root: &str,
+    mut visit: impl FnMut(&Path, tar::Entry<'_, R>) -> Result<(), EcoString>,
+) -> Result<(), EcoString> {
+    let mut archive = tar::Archive::new(archive);
+    let mut count = 0;
+    for entry in archive.entries().map_err(|error| eco_format!("{error}"))? {
+        let entry = entry.map_err(|error| eco_format!("{error}"))?;
+        let entry_type = entry.header().entry_type();
+        if entry_type == tar::EntryType::XGlobalHeader {
+            continue;
+        }
+
+        count += 1;
+        if count > limits.max_entries {
+            return Err(eco_format!("more than {} entries", limits.max_entries));
+        }
+
+        let entry_path = entry
+            .path()
+            .map_err(|error| eco_format!("{error}"))?
+            .into_owned();
+        let shown = entry_path.display();
+        match entry_type {
+            tar::EntryType::Regular | tar::EntryType::Directory => {}
+            tar::EntryType::Symlink | tar::EntryType::Link => {
+                return Err(eco_format!("link at {shown} is not allowed"));
+            }
+            other => return Err(eco_format!("unsupported entry {other:?} at {shown}")),
+        }
+        if !is_contained(&entry_path) {
+            return Err(eco_format!("path {shown} escapes the {root}"));
+        }
+
+        visit(&entry_path, entry)?;
+    }
+
+    Ok(())
+}
+
+/// Whether `path` stays inside the directory it is relative to, i.e. is neither absolute nor
+/// contains `..`.
+pub(crate) fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Takes an exclusive file lock for changing `package` at `path`, waiting for other processes
+/// sharing the cache directory to finish their download first.
+fn lock_file(package: &PackageSpec, path: &Path) -> std::io::Result<std::fs::File> {
+    let parent = path.parent().unwrap_or(path);
+    std::fs::create_dir_all(parent)?;
+    let file = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(parent.join(format!(".{}.lock", package.version)))?;
+    file.lock()?;
+
+    Ok(file)
+}
+
+/// Directory of `package` below the package path or the cache, e.g. `preview/example/0.1.0`.
+fn package_subdir(package: &PackageSpec) -> String {
+    format!("{}/{}/{}", package.namespace, package.name, package.version)
+}
+
+/// Records that the cached package at `path` was used, by updating its modification time.
+fn mark_used(path: &Path) {
+    if let Err(error) =
+        std::fs::File::open(path).and_then(|dir| dir.set_modified(SystemTime::now()))
+    {
+        tracing::debug!("Failed to mark {} as used: {error}", path.display());
+    }
+}
+
+/// Names of the visible subdirectories of `path`, which is empty if it does not exist.
+fn subdirectories(path: &Path) -> std::io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Ok(name) = entry.file_name().into_string()
+            && !name.starts_with('.')
+            && entry.file_type()?.is_dir()
+        {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Total size of the files below `path`.
+fn directory_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// A package in the cache directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedPackage {
+    #[serde(serialize_with = "serialize_display")]
+    pub package: PackageSpec,
+    /// Size of the unpacked package in bytes.
+    pub size: u64,
+    /// When the package was last resolved from the cache.
+    #[serde(serialize_with = "time::serde::rfc3339::serialize")]
+    pub last_used: time::OffsetDateTime,
+}
+
+fn serialize_display<S: Serializer>(
+    value: &impl std::fmt::Display,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// Packages imported or included by the Typst sources in `dir` and its subdirectories.
+fn package_imports(dir: &Path) -> Vec<PackageSpec> {
+    fn visit(dir: &Path, packages: &mut Vec<PackageSpec>) {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut paths: Vec<_> = entries.flatten().map(|entry| entry.path()).collect();
+        paths.sort();
+        for path in paths {
+            if path.is_dir() {
+                visit(&path, packages);
+            } else if path.extension().is_some_and(|extension| extension == "typ")
+                && let Ok(source) = std::fs::read_to_string(&path)
+            {
+                for package in imported_packages(&source) {
+                    if !packages.contains(&package) {
+                        packages.push(package);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut packages = Vec::new();
+    visit(dir, &mut packages);
+    packages
+}
+
+/// Packages imported or included by a Typst source, in order of first appearance.
+pub fn imported_packages(source: &str) -> Vec<PackageSpec> {
+    fn visit(node: &SyntaxNode, packages: &mut Vec<PackageSpec>) {
+        let import_source = node
+            .cast::<ast::ModuleImport>()
+            .map(ast::ModuleImport::source)
+            .or_else(|| {
+                node.cast::<ast::ModuleInclude>()
+                    .map(ast::ModuleInclude::source)
+            });
+        if let Some(ast::Expr::Str(path)) = import_source
+            && let Ok(spec) = path.get().parse::<PackageSpec>()
+            && !packages.contains(&spec)
+        {
+            packages.push(spec);
+        }
+
+        for child in node.children() {
+            visit(child, packages);
+        }
+    }
+
+    let mut packages = Vec::new();
+    visit(&typst::syntax::parse(source), &mut packages);
+    packages
+}
+
+/// A package that could not be resolved.
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageFailure {
+    pub package: String,
+    pub error: String,
+}
+
+/// Packages that must be available before the server accepts traffic.
+#[derive(Debug)]
+pub struct PinnedPackages {
+    packages: Vec<PackageSpec>,
+    storage: Arc<PackageStorage>,
+}
+
+impl PinnedPackages {
+    /// Also protects `packages` from being evicted from the cache of `storage`.
+    pub fn new(packages: Vec<PackageSpec>, storage: Arc<PackageStorage>) -> Self {
+        storage.pin(&packages);
+        Self { packages, storage }
+    }
+
+    pub fn packages(&self) -> &[PackageSpec] {
+        &self.packages
+    }
+
+    /// Pinned packages that are neither in the package path nor in the cache.
+    ///
+    /// Only looks at the file system, so unlike [`Self::resolve`] it never waits for a download.
+    pub fn missing(&self) -> Vec<PackageFailure> {
+        self.packages
+            .iter()
+            .filter(|package| !self.storage.is_available_locally(package))
+            .map(|package| PackageFailure {
+                package: package.to_string(),
+                error: "not in the package path or the cache".to_owned(),
+            })
+            .collect()
+    }
+
+    /// Resolves every pinned package, downloading the missing ones.
+    ///
+    /// This blocks on network I/O, so it must not run on the async runtime.
+    pub fn resolve(&self) -> Vec<PackageFailure> {
+        self.packages
+            .iter()
+            .filter_map(|package| match self.storage.prepare_package(package) {
+                Ok(path) => {
+                    tracing::debug!("{package} resolved to {}", path.display());
+                    None
+                }
+                Err(error) => {
+                    tracing::error!("Failed to resolve {package}: {error}");
+                    Some(PackageFailure {
+                        package: package.to_string(),
+                        error: error.to_string(),
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_registry::{TestRegistry, crafted_archive, package_archive};
+
+    fn spec() -> PackageSpec {
+        "@preview/example:0.1.0".parse().expect("valid spec")
+    }
+
+    fn storage(package_path: Option<PathBuf>, cache_directory: PathBuf) -> PackageStorage {
+        PackageStorage::new(PackageConfig {
+            package_path,
+            cache_directory,
+            offline: true,
+            ..Default::default()
+        })
+    }
+
+    fn online_storage(cache_directory: &Path, registry: &TestRegistry) -> PackageStorage {
+        PackageStorage::new(PackageConfig {
+            cache_directory: cache_directory.into(),
+            registry_url: registry.url(),
+            namespace_registries: HashMap::from([("acme".to_owned(), registry.url())]),
+            ..Default::default()
+        })
+    }
+
+    fn example_archive() -> Vec<u8> {
+        package_archive(&[
+            (
+                "typst.toml",
+                b"[package]\nname = \"example\"\nversion = \"0.1.0\"\nentrypoint = \"lib.typ\"\n",
+            ),
+            ("lib.typ", b"#let hello = [Hello]"),
+        ])
+    }
+
+    #[test]
+    fn packages_are_downloaded_from_the_configured_registry() {
+        let registry = TestRegistry::start();
+        registry.add_package("preview", "example", "0.1.0", example_archive());
+        let cache = tempfile::tempdir().expect("tempdir");
+
+        let path = online_storage(cache.path(), &registry)
+            .prepare_package(&spec())
+            .expect("package downloads");
+
+        assert_eq!(path, cache.path().join("preview/example/0.1.0"));
+        assert!(path.join("lib.typ").exists());
+        assert_eq!(registry.requests(), ["/preview/example-0.1.0.tar.gz"]);
+    }
+
+    #[test]
+    fn private_namespaces_use_their_mirror() {
+        let registry = TestRegistry::start();
+        registry.add_package("acme", "letterhead", "1.0.0", example_archive());
+        let cache = tempfile::tempdir().expect("tempdir");
+        let storage = online_storage(cache.path(), &registry);
+
+        let letterhead = "@acme/letterhead:1.0.0".parse().expect("valid spec");
+        storage
+            .prepare_package(&letterhead)
+            .expect("package downloads");
+
+        let unknown = "@unknown/letterhead:1.0.0".parse().expect("valid spec");
+        assert!(matches!(
+            storage.prepare_package(&unknown),
+            Err(PackageError::NotFound(_))
+        ));
+        assert_eq!(registry.requests(), ["/acme/letterhead-1.0.0.tar.gz"]);
+    }
+
+    #[test]
+    fn failed_downloads_are_retried_once() {
+        let registry = TestRegistry::start();
+        registry.add_package("preview", "example", "0.1.0", example_archive());
+        let cache = tempfile::tempdir().expect("tempdir");
+        let storage = online_storage(cache.path(), &registry);
+
+        registry.fail_next(1);
+        storage.prepare_package(&spec()).expect("retry succeeds");
+        assert_eq!(registry.requests().len(), 2);
+
+        let other: PackageSpec = "@preview/example:0.2.0".parse().expect("valid spec");
+        registry.add_package("preview", "example", "0.2.0", example_archive());
+        registry.fail_next(2);
+        assert!(matches!(
+            storage.prepare_package(&other),
+            Err(PackageError::NetworkFailed(_))
+        ));
+    }
+
+    #[test]
+    fn locked_archives_must_match_their_hash() {
+        let registry = TestRegistry::start();
+        registry.add_package("preview", "example", "0.1.0", example_archive());
+        let other: PackageSpec = "@preview/example:0.2.0".parse().expect("valid spec");
+        registry.add_package("preview", "example", "0.2.0", example_archive());
+        let cache = tempfile::tempdir().expect("tempdir");
+
+        let mut lockfile = Lockfile::default();
+        lockfile.insert(&spec(), archive_hash(&example_archive()));
+        lockfile.insert(&other, archive_hash(b"re-published"));
+        let lockfile_path = cache.path().join("typst-packages.lock");
+        lockfile.save(&lockfile_path).expect("lockfile is written");
+        assert_eq!(
+            Lockfile::load(&lockfile_path).expect("lockfile is read"),
+            lockfile
+        );
+
+        let storage = PackageStorage::new(PackageConfig {
+            lockfile: Some(lockfile_path),
+            ..online_storage(cache.path(), &registry).config
+        });
+        storage.prepare_package(&spec()).expect("hash matches");
+        assert!(matches!(
+            storage.prepare_package(&other),
+            Err(PackageError::MalformedArchive(_))
+        ));
+        assert!(!cache.path().join("preview/example/0.2.0").exists());
+        assert_eq!(
+            storage
+                .inspect_archive(&other)
+                .expect("archive downloads")
+                .hash,
+            archive_hash(&example_archive())
+        );
+
+        let unlisted: PackageSpec = "@preview/example:0.3.0".parse().expect("valid spec");
+        registry.add_package("preview", "example", "0.3.0", example_archive());
+        let requests = registry.requests().len();
+        assert!(matches!(
+            storage.prepare_package(&unlisted),
+            Err(PackageError::Other(Some(message))) if message.contains("not listed")
+        ));
+        assert_eq!(registry.requests().len(), requests, "nothing is downloaded");
+    }
+
+    #[test]
+    fn inspected_archives_list_their_imports() {
+        let registry = TestRegistry::start();
+        let archive = package_archive(&[
+            (
+                "typst.toml",
+                b"[package]\nname = \"wrapper\"\nversion = \"0.1.0\"\nentrypoint = \"lib.typ\"\n",
+            ),
+            (
+                "lib.typ",
+                b"#import \"@preview/example:0.1.0\": hello\n#import \"src/more.typ\"",
+            ),
+            (
+                "src/more.typ",
+                b"#import \"@preview/example:0.1.0\"\n#include \"@acme/other:1.0.0\"",
+            ),
+        ]);
+        registry.add_package("preview", "wrapper", "0.1.0", archive.clone());
+        let cache = tempfile::tempdir().expect("tempdir");
+        let storage = online_storage(cache.path(), &registry);
+
+        let wrapper: PackageSpec = "@preview/wrapper:0.1.0".parse().expect("valid spec");
+        let inspected = storage
+            .inspect_archive(&wrapper)
+            .expect("archive downloads");
+        assert_eq!(inspected.hash, archive_hash(&archive));
+        let imports: Vec<_> = inspected.imports.iter().map(ToString::to_string).collect();
+        assert_eq!(imports, ["@preview/example:0.1.0", "@acme/other:1.0.0"]);
+        assert!(
+            !cache.path().join("preview/wrapper").exists(),
+            "inspected packages are not cached"
+        );
+    }
+
+    /// Puts a package of `size` bytes into the cache, last used `last_used` seconds after the epoch.
+    fn cache_package(cache_directory: &Path, package: &str, size: usize, last_used: u64) {
+        let package: PackageSpec = package.parse().expect("valid spec");
+        let path = cache_directory.join(format!(
+            "{}/{}/{}",
+            package.namespace, package.name, package.version
+        ));
+        std::fs::create_dir_all(&path).expect("package dir is created");
+        std::fs::write(path.join("typst.toml"), "").expect("manifest is written");
+        std::fs::write(path.join("lib.typ"), vec![b'a'; size]).expect("package file is written");
+        std::fs::File::open(&path)
+            .and_then(|dir| {
+                dir.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(last_used))
+            })
+            .expect("last use is set");
+    }
+
+    #[test]
+    fn cached_packages_are_listed_and_purged() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        cache_package(cache.path(), "@preview/example:0.2.0", 20, 200);
+        cache_package(cache.path(), "@preview/example:0.1.0", 10, 100);
+        std::fs::write(cache.path().join("preview/example/.0.1.0.lock"), "").expect("lock");
+        std::fs::create_dir_all(cache.path().join("unrelated/directory")).expect("unrelated dir");
+        std::fs::create_dir_all(cache.path().join("other/software/1.0.0")).expect("foreign dir");
+        let storage = storage(None, cache.path().into());
+
+        let cached = storage.cached_packages().expect("cache is listed");
+        let listed: Vec<_> = cached
+            .iter()
+            .map(|cached| (cached.package.to_string(), cached.size))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("@preview/example:0.1.0".to_owned(), 10),
+                ("@preview/example:0.2.0".to_owned(), 20)
+            ]
+        );
+        assert_eq!(cached[0].last_used.unix_timestamp(), 100);
+
+        assert!(storage.purge(&spec()).expect("package is purged"));
+        assert!(!storage.purge(&spec()).expect("package is gone"));
+        assert_eq!(storage.cached_packages().expect("cache is listed").len(), 1);
+    }
+
+    #[test]
+    fn least_recently_used_packages_are_evicted_unless_pinned() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        cache_package(cache.path(), "@preview/pinned:1.0.0", 10, 100);
+        cache_package(cache.path(), "@preview/old:1.0.0", 10, 200);
+        cache_package(cache.path(), "@preview/recent:1.0.0", 10, 300);
+        cache_package(cache.path(), "@preview/newest:1.0.0", 10, 400);
+        let storage = PackageStorage::new(PackageConfig {
+            max_cache_size: Some(25),
+            ..storage(None, cache.path().into()).config
+        });
+        storage.pin(&["@preview/pinned:1.0.0".parse().expect("valid spec")]);
+
+        let evicted: Vec<_> = storage
+            .enforce_cache_limit(None)
+            .expect("cache limit is enforced")
+            .iter()
+            .map(|cached| cached.package.to_string())
+            .collect();
+
+        assert_eq!(evicted, ["@preview/old:1.0.0", "@preview/recent:1.0.0"]);
+        assert!(cache.path().join("preview/pinned/1.0.0").exists());
+        assert!(cache.path().join("preview/newest/1.0.0").exists());
+    }
+
+    #[test]
+    fn retained_packages_are_not_evicted() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        cache_package(cache.path(), "@preview/rendering:1.0.0", 10, 100);
+        cache_package(cache.path(), "@preview/newest:1.0.0", 10, 200);
+        let storage = PackageStorage::new(PackageConfig {
+            max_cache_size: Some(15),
+            ..storage(None, cache.path().into()).config
+        });
+        let rendering = "@preview/rendering:1.0.0".parse().expect("valid spec");
+
+        storage.retain(&rendering);
+        storage.retain(&rendering);
+        storage.release(&rendering);
+        let evicted = storage
+            .enforce_cache_limit(None)
+            .expect("cache limit is enforced");
+        assert_eq!(evicted[0].package.to_string(), "@preview/newest:1.0.0");
+        assert!(cache.path().join("preview/rendering/1.0.0").exists());
+
+        cache_package(cache.path(), "@preview/newest:1.0.0", 10, 200);
+        storage.release(&rendering);
+        let evicted = storage
+            .enforce_cache_limit(None)
+            .expect("cache limit is enforced");
+        assert_eq!(evicted[0].package, rendering);
+    }
+
+    #[test]
+    fn concurrent_requests_share_one_download() {
+        let registry = TestRegistry::start();
+        registry.add_package("preview", "example", "0.1.0", example_archive());
+        let cache = tempfile::tempdir().expect("tempdir");
+        let storage = online_storage(cache.path(), &registry);
+
+        std::thread::scope(|scope| {
+            let waiters: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| storage.prepare_package(&spec())))
+                .collect();
+            for waiter in waiters {
+                waiter
+                    .join()
+                    .expect("thread finished")
+                    .expect("package resolves");
+            }
+        });
+
+        assert_eq!(registry.requests().len(), 1);
+    }
+
+    #[test]
+    fn malformed_archives_are_rejected() {
+        let registry = TestRegistry::start();
+        registry.add_package("preview", "example", "0.1.0", b"not a tarball".to_vec());
+        let cache = tempfile::tempdir().expect("tempdir");
+
+        let result = online_storage(cache.path(), &registry).prepare_package(&spec());
+
+        assert!(matches!(result, Err(PackageError::MalformedArchive(_))));
+        // Neither the package nor a partially unpacked directory is left behind.
+        let leftovers: Vec<_> = std::fs::read_dir(cache.path().join("preview/example"))
+            .expect("package parent exists")
+            .map(|entry| entry.expect("entry").file_name())
+            .collect();
+        assert_eq!(leftovers, [".0.1.0.lock"]);
+    }
+
+    /// A registry answering every request with `response` (if any) without reading it, then
+    /// leaving the connection open until the client gives up.
+    fn raw_registry(response: Option<Vec<u8>>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("listener binds");
+        let url = format!(
+            "http://{}",
+            listener.local_addr().expect("listener has an address")
+        );
+        std::thread::spawn(move || {
+            let mut connections = Vec::new();
+            for mut stream in listener.incoming().flatten() {
+                if let Some(response) = &response {
+                    _ = std::io::Write::write_all(&mut stream, response);
+                }
+                connections.push(stream);
+            }
+        });
+        url
+    }
+
+    fn raw_storage(cache_directory: &Path, url: String) -> PackageStorage {
+        PackageStorage::new(PackageConfig {
+            cache_directory: cache_directory.into(),
+            registry_url: url,
+            download_timeout: std::time::Duration::from_millis(300),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn stalled_downloads_time_out() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        let storage = raw_storage(cache.path(), raw_registry(None));
+
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            storage.prepare_package(&spec()),
+            Err(PackageError::NetworkFailed(_))
+        ));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn broken_off_downloads_are_network_failures() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        let archive = example_archive();
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            archive.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&archive[..archive.len() / 2]);
+        let storage = raw_storage(cache.path(), raw_registry(Some(response)));
+
+        assert!(matches!(
+            storage.prepare_package(&spec()),
+            Err(PackageError::NetworkFailed(_))
+        ));
+        assert!(!cache.path().join("preview/example/0.1.0").exists());
+    }
+
+    /// Downloads `archive` as the example package with small extraction limits.
+    fn download_crafted(cache_directory: &Path, archive: Vec<u8>) -> PackageResult<PathBuf> {
+        let registry = TestRegistry::start();
+        registry.add_package("preview", "example", "0.1.0", archive);
+        PackageStorage::new(PackageConfig {
+            extraction_limits: ExtractionLimits {
+                max_decompressed_size: 1024 * 1024,
+                max_entries: 3,
+            },
+            ..online_storage(cache_directory, &registry).config
+        })
+        .prepare_package(&spec())
+    }
+
+    fn assert_rejected(result: PackageResult<PathBuf>, reason: &str) {
+        match result {
+            Err(PackageError::MalformedArchive(Some(message))) => assert!(
+                message.contains(reason),
+                "`{message}` does not mention `{reason}`"
+            ),
+            other => panic!("expected a malformed archive, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn gzip_bombs_are_rejected() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        let zeros = vec![0; 4 * 1024 * 1024];
+        let archive = crafted_archive(&[("lib.typ", tar::EntryType::Regular, &zeros)]);
+        assert!(archive.len() < 64 * 1024);
+
+        assert_rejected(
+            download_crafted(cache.path(), archive),
+            "exceeds 1048576 bytes when decompressed",
+        );
+        assert!(!cache.path().join("preview/example/0.1.0").exists());
+    }
+
+    #[test]
+    fn paths_escaping_the_package_are_rejected() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        let outside = cache.path().join("absolute.typ");
+
+        for path in [
+            "../escape.typ",
+            "nested/../../escape.typ",
+            outside.to_str().unwrap(),
+        ] {
+            let archive = crafted_archive(&[(path, tar::EntryType::Regular, b"#panic()")]);
+            assert_rejected(
+                download_crafted(cache.path(), archive),
+                "escapes the package directory",
+            );
+        }
+        assert!(!cache.path().join("preview/example/escape.typ").exists());
+        assert!(!cache.path().join("preview/escape.typ").exists());
+        assert!(!outside.exists());
+    }
+
+    #[test]
+    fn links_are_rejected() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        for entry_type in [tar::EntryType::Symlink, tar::EntryType::Link] {
+            let archive = crafted_archive(&[("link.typ", entry_type, b"")]);
+            assert_rejected(download_crafted(cache.path(), archive), "link at link.typ");
+        }
+    }
+
+    #[test]
+    fn archives_with_too_many_entries_are_rejected() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        let entries: Vec<_> = ["a.typ", "b.typ", "c.typ", "d.typ"]
+            .into_iter()
+            .map(|path| (path, tar::EntryType::Regular, b"".as_slice()))
+            .collect();
+
+        assert_rejected(
+            download_crafted(cache.path(), crafted_archive(&entries)),
+            "more than 3 entries",
+        );
+        download_crafted(cache.path(), crafted_archive(&entries[..3])).expect("within limits");
+    }
+
+    #[test]
+    fn imports_and_includes_are_collected_once() {
+        let source = r#"
+#import "@preview/classy-german-invoice:0.3.1": invoice
+#import "@preview/classy-german-invoice:0.3.1"
+#import "local.typ": helper
+#include "@local/letterhead:1.0.0"
+#let x = "@preview/not-an-import:1.0.0"
+"#;
+
+        let packages: Vec<_> = imported_packages(source)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            packages,
+            [
+                "@preview/classy-german-invoice:0.3.1",
+                "@local/letterhead:1.0.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn pinned_packages_report_failures() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        std::fs::create_dir_all(cache.path().join("preview/example/0.1.0"))
+            .expect("create package");
+        let missing: PackageSpec = "@preview/missing:1.0.0".parse().expect("valid spec");
+
+        let pinned = PinnedPackages::new(
+            vec![spec(), missing],
+            Arc::new(storage(None, cache.path().into())),
+        );
+
+        let failures = pinned.resolve();
+        let [failure] = failures.as_slice() else {
+            panic!("expected one failure, got {failures:?}");
+        };
+        assert_eq!(failure.package, "@preview/missing:1.0.0");
+    }
+
+    #[test]
+    fn missing_pinned_packages_are_not_downloaded() {
+        let registry = TestRegistry::start();
+        registry.add_package("preview", "example", "0.1.0", example_archive());
+        let cache = tempfile::tempdir().expect("tempdir");
+        let pinned = PinnedPackages::new(
+            vec![spec()],
+            Arc::new(online_storage(cache.path(), &registry)),
+        );
+
+        let missing = pinned.missing();
+        let [failure] = missing.as_slice() else {
+            panic!("expected one missing package, got {missing:?}");
+        };
+        assert_eq!(failure.package, "@preview/example:0.1.0");
+        assert!(registry.requests().is_empty());
+
+        assert!(pinned.resolve().is_empty());
+        assert!(pinned.missing().is_empty());
+    }
+
+    #[test]
+    fn package_path_is_searched_before_the_cache() {
+        let local = tempfile::tempdir().expect("tempdir");
+        let cache = tempfile::tempdir().expect("tempdir");
+        for dir in [local.path(), cache.path()] {
+            std::fs::create_dir_all(dir.join("preview/example/0.1.0")).expect("create package");
+        }
+
+        let storage = storage(Some(local.path().into()), cache.path().into());
+
+        assert_eq!(
+            storage.prepare_package(&spec()).expect("package resolves"),
+            local.path().join("preview/example/0.1.0")
+        );
+    }
+
+    #[test]
+    fn cached_packages_resolve_offline() {
+        let cache = tempfile::tempdir().expect("tempdir");
+        std::fs::create_dir_all(cache.path().join("preview/example/0.1.0"))
+            .expect("create package");
+
+        let storage = storage(None, cache.path().into());
+
+        assert_eq!(
+            storage.prepare_package(&spec()).expect("package resolves"),
+            cache.path().join("preview/example/0.1.0")
+        );
+    }
+
+    #[test]
+    fn missing_packages_fail_fast_offline() {
+        let cache = tempfile::tempdir().expect("tempdir");
+
+        let storage = storage(None, cache.path().into());
+
+        let Err(PackageError::Other(Some(message))) = storage.prepare_package(&spec()) else {
+            panic!("offline lookup of a missing package should fail");
+        };
+        assert!(message.contains("offline"), "{message}");
+    }
+}
@@ -0,0 +1,215 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use crate::templates::AppError;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Sizing of a [`RenderPool`].
+#[derive(Debug, Clone)]
+pub struct RenderPoolConfig {
+    /// Number of threads compiling templates concurrently.
+    pub workers: usize,
+    /// Number of jobs allowed to wait for a free worker before new ones are rejected.
+    pub queue_depth: usize,
+    /// Suggested delay for clients whose job was rejected.
+    pub retry_after: Duration,
+}
+
+impl Default for RenderPoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_depth: 32,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RenderPoolConfig {
+    /// Reads `RENDER_WORKERS`, `RENDER_QUEUE_DEPTH` and `RENDER_RETRY_AFTER_SECS`,
+    /// falling back to the defaults for unset or unparsable values.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+
+        let default = Self::default();
+        Self {
+            workers: var("RENDER_WORKERS").unwrap_or(default.workers).max(1),
+            queue_depth: var("RENDER_QUEUE_DEPTH").unwrap_or(default.queue_depth),
+            retry_after: var("RENDER_RETRY_AFTER_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.retry_after),
+        }
+    }
+}
+
+/// Dedicated threads for the CPU-heavy and blocking Typst compilation, so that it never runs
+/// on the async runtime.
+///
+/// At most `workers + queue_depth` jobs are admitted at once; beyond that, jobs are rejected
+/// with [`AppError::Overloaded`] instead of piling up.
+pub struct RenderPool {
+    sender: Sender<Job>,
+    admitted: Arc<AtomicUsize>,
+    capacity: usize,
+    retry_after: Duration,
+}
+
+impl RenderPool {
+    pub fn new(config: RenderPoolConfig) -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..config.workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            std::thread::Builder::new()
+                .name(format!("render-worker-{index}"))
+                .spawn(move || worker_loop(&receiver))
+                .expect("Failed to spawn render worker");
+        }
+
+        Self {
+            sender,
+            admitted: Arc::new(AtomicUsize::new(0)),
+            capacity: config.workers.max(1) + config.queue_depth,
+            retry_after: config.retry_after,
+        }
+    }
+
+    /// Runs `job` on a worker and waits for its result.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, AppError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(job)?
+            .await
+            .map_err(|_| AppError::InternalServerError)
+    }
+
+    /// Queues `job` without waiting for it, or rejects it if the pool is saturated.
+    pub fn submit<T, F>(&self, job: F) -> Result<oneshot::Receiver<T>, AppError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = self.admit()?;
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.sender
+            .send(Box::new(move || {
+                let result = job();
+                // Release the slot before replying, so that a caller can immediately submit again.
+                // If the job panics, unwinding releases it instead.
+                drop(permit);
+                _ = result_sender.send(result);
+            }))
+            .map_err(|_| AppError::InternalServerError)?;
+
+        Ok(result_receiver)
+    }
+
+    fn admit(&self) -> Result<Permit, AppError> {
+        self.admitted
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |admitted| {
+                (admitted < self.capacity).then_some(admitted + 1)
+            })
+            .map_err(|_| {
+                tracing::warn!("Render pool saturated, rejecting job");
+                AppError::Overloaded {
+                    retry_after: self.retry_after,
+                }
+            })?;
+
+        Ok(Permit(Arc::clone(&self.admitted)))
+    }
+}
+
+/// A slot in the pool, given back on drop.
+struct Permit(Arc<AtomicUsize>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn worker_loop(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Ok(job) = job else {
+            // The pool was dropped.
+            return;
+        };
+
+        if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            tracing::error!("Render job panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_jobs_off_the_runtime() {
+        let pool = RenderPool::new(RenderPoolConfig::default());
+
+        let thread_name = pool
+            .run(|| std::thread::current().name().map(str::to_owned))
+            .await
+            .expect("job should run");
+
+        assert!(thread_name.is_some_and(|name| name.starts_with("render-worker-")));
+    }
+
+    #[tokio::test]
+    async fn rejects_jobs_beyond_queue_depth() {
+        let pool = RenderPool::new(RenderPoolConfig {
+            workers: 1,
+            queue_depth: 1,
+            retry_after: Duration::from_secs(7),
+        });
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+
+        let running = pool.submit(move || blocked.recv()).expect("worker is free");
+        let queued = pool.submit(|| 42).expect("queue has room");
+
+        assert!(matches!(
+            pool.submit(|| 0),
+            Err(AppError::Overloaded { retry_after }) if retry_after == Duration::from_secs(7)
+        ));
+
+        release.send(()).expect("worker is waiting");
+        running.await.expect("job ran").expect("job was released");
+        assert_eq!(queued.await.expect("queued job ran"), 42);
+
+        assert_eq!(pool.run(|| 1).await.expect("pool has room again"), 1);
+    }
+
+    #[tokio::test]
+    async fn panicking_job_frees_its_slot() {
+        let pool = RenderPool::new(RenderPoolConfig {
+            workers: 1,
+            queue_depth: 0,
+            retry_after: Duration::from_secs(1),
+        });
+
+        assert!(matches!(
+            pool.run(|| panic!("boom")).await,
+            Err::<(), _>(AppError::InternalServerError)
+        ));
+        assert_eq!(pool.run(|| 1).await.expect("worker survived"), 1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Result,
+};
+use tracing::{info, instrument};
+use typst_pdf_api::TypstWrapperWorld;
+use typst_pdf_api::fonts::{Coverage, FontEntry, FontFace, Registration, TenantFonts};
+use typst_pdf_api::render_pool::RenderPool;
+
+#[derive(serde::Deserialize, Debug)]
+pub struct FontQuery {
+    /// Include the fonts registered by this tenant.
+    pub tenant: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct CoverageQuery {
+    pub family: String,
+    pub text: String,
+    /// Include the fonts registered by this tenant.
+    pub tenant: Option<String>,
+}
+
+/// The world renders of `tenant` get, without a source.
+fn font_world(tenant: Option<&str>) -> TypstWrapperWorld {
+    let world = TypstWrapperWorld::with_source(String::new());
+    match tenant {
+        Some(tenant) => world.with_tenant(tenant),
+        None => world,
+    }
+}
+
+/// Lists every font available to templates, with its file or whether it is embedded.
+#[instrument(skip(render_pool))]
+pub async fn list_fonts_controller(
+    State(render_pool): State<Arc<RenderPool>>,
+    Query(query): Query<FontQuery>,
+) -> Result<Json<Vec<FontEntry>>> {
+    let entries = render_pool
+        .run(move || font_world(query.tenant.as_deref()).font_entries())
+        .await?;
+    Ok(Json(entries))
+}
+
+/// Checks whether a font family has a glyph for every character of a text.
+#[instrument(skip(render_pool))]
+pub async fn font_coverage_controller(
+    State(render_pool): State<Arc<RenderPool>>,
+    Query(query): Query<CoverageQuery>,
+) -> Result<Json<Coverage>> {
+    let coverage = render_pool
+        .run(move || font_world(query.tenant.as_deref()).font_coverage(&query.family, &query.text))
+        .await?;
+    Ok(Json(coverage))
+}
+
+/// Registers the font file in the body for the renders of a tenant.
+///
+/// Faces the tenant already has with the same family and variant are reported as duplicates.
+#[instrument(skip(render_pool, tenant_fonts, body))]
+pub async fn register_tenant_font_controller(
+    State(render_pool): State<Arc<RenderPool>>,
+    State(tenant_fonts): State<Arc<TenantFonts>>,
+    Path(tenant): Path<String>,
+    body: Bytes,
+) -> Result<Json<Registration>> {
+    let registration = render_pool
+        .run(move || tenant_fonts.register(&tenant, body.to_vec()))
+        .await??;
+    info!(
+        "Registered {} font face(s), skipped {} duplicate(s)",
+        registration.added.len(),
+        registration.duplicates.len()
+    );
+    Ok(Json(registration))
+}
+
+/// Lists the font faces registered by a tenant.
+#[instrument(skip(tenant_fonts))]
+pub async fn list_tenant_fonts_controller(
+    State(tenant_fonts): State<Arc<TenantFonts>>,
+    Path(tenant): Path<String>,
+) -> Json<Vec<FontFace>> {
+    Json(
+        tenant_fonts
+            .get(&tenant)
+            .map(|set| set.faces())
+            .unwrap_or_default(),
+    )
+}
+
+/// Removes all fonts of a tenant.
+#[instrument(skip(tenant_fonts))]
+pub async fn remove_tenant_fonts_controller(
+    State(tenant_fonts): State<Arc<TenantFonts>>,
+    Path(tenant): Path<String>,
+) -> StatusCode {
+    if tenant_fonts.remove(&tenant) {
+        info!("Removed the fonts of {tenant}");
+    }
+    StatusCode::NO_CONTENT
+}
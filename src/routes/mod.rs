@@ -1,44 +1,520 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use axum::{
     Json,
-    http::{HeaderMap, header},
-    response::{IntoResponse, Result},
+    extract::{FromRef, Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response, Result},
 };
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde_json::Value;
 use tracing::{info, instrument};
-use typst_pdf_api::templates::{AppError, german_invoice::GERMAN_INVOICE_TEMPLATE};
+use typst_pdf_api::fonts::TenantFonts;
+use typst_pdf_api::packages::{CachedPackage, PackageFailure, PackageStorage, PinnedPackages};
+use typst_pdf_api::render_pool::RenderPool;
+use typst_pdf_api::templates::{
+    AppError, AppJson, OutputFormat, RenderOptions, Rendered,
+    diagnostics::{self, Diagnostic},
+    german_invoice::{self, GermanTemplateData},
+    registry::{TemplateDefinition, TemplateRegistry},
+};
+
+mod fonts;
+mod upload;
+
+pub use fonts::{
+    font_coverage_controller, list_fonts_controller, list_tenant_fonts_controller,
+    register_tenant_font_controller, remove_tenant_fonts_controller,
+};
+pub use upload::{UploadLimits, bundle_controller, upload_controller};
+
+/// Shared state of all routes.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub registry: Arc<TemplateRegistry>,
+    pub render_pool: Arc<RenderPool>,
+    pub pinned_packages: Arc<PinnedPackages>,
+    pub package_storage: Arc<PackageStorage>,
+    pub tenant_fonts: Arc<TenantFonts>,
+    pub upload_limits: UploadLimits,
+}
 
 // #[axum::debug_handler]
-#[instrument]
+#[instrument(skip(registry, render_pool, payload), fields(template_id = %payload.template_id))]
 pub async fn pdf_generation_controller(
-    Json(_payload): Json<CreatePDF>,
+    State(registry): State<Arc<TemplateRegistry>>,
+    State(render_pool): State<Arc<RenderPool>>,
+    AppJson(payload): AppJson<CreatePDF>,
 ) -> Result<impl IntoResponse> {
     info!("Serving PDF");
-    // This is where you would implement the logic to convert a template to PDF.
-    // For now, we return a simple string.
-    let german_template = GERMAN_INVOICE_TEMPLATE;
+    let template = registry.get(&payload.template_id)?;
+    let (options, format) = payload.params.into_options();
+
+    let files = payload
+        .files
+        .into_iter()
+        .map(|(path, content)| {
+            BASE64
+                .decode(content)
+                .map(|content| (path.clone(), content))
+                .map_err(|error| AppError::InvalidData(format!("files.{path}: {error}")))
+        })
+        .collect::<Result<_, _>>()?;
+    let options = RenderOptions {
+        // Without data, the template gets no inputs at all rather than `data: none`.
+        data: (!payload.data.is_null()).then_some(payload.data),
+        root: registry.root_of(template).map(ToOwned::to_owned),
+        files,
+        ..options
+    };
+
+    let source = template.source.clone();
+    let rendered = render_pool
+        .run(move || typst_pdf_api::templates::render_template(source, options, format))
+        .await??;
+
+    info!("PDF Served");
+    Ok(render_response(rendered, "output")?)
+}
+
+/// Renders a validated German invoice.
+#[instrument(skip(registry, render_pool, invoice), fields(invoice_number = %invoice.invoice_number))]
+pub async fn german_invoice_controller(
+    State(registry): State<Arc<TemplateRegistry>>,
+    State(render_pool): State<Arc<RenderPool>>,
+    Query(params): Query<RenderParams>,
+    AppJson(invoice): AppJson<GermanTemplateData>,
+) -> Result<impl IntoResponse> {
+    info!("Serving German invoice");
+    invoice.validate()?;
+
+    let (options, format) = params.into_options();
+    let template = registry.get(german_invoice::TEMPLATE_ID)?;
+    let (data, files) = invoice.into_template_input()?;
+    let options = RenderOptions {
+        data: Some(data),
+        root: registry.root_of(template).map(ToOwned::to_owned),
+        files,
+        ..options
+    };
+    let source = template.source.clone();
+    let rendered = render_pool
+        .run(move || typst_pdf_api::templates::render_template(source, options, format))
+        .await??;
+
+    info!("German invoice served");
+    Ok(render_response(rendered, "invoice")?)
+}
 
-    let pdf_buf = typst_pdf_api::templates::template_to_pdf(german_template.to_string())?;
+/// Header with the number of Typst warnings emitted during the render.
+const WARNING_COUNT_HEADER: &str = "x-typst-warning-count";
+/// Repeated once per Typst warning, as `file:line:column: message`.
+const WARNING_HEADER: &str = "x-typst-warning";
+/// Warnings beyond this many are only counted, so that a template emitting a warning per page
+/// cannot exceed the header size limits of clients and proxies.
+const MAX_WARNING_HEADERS: usize = 20;
+
+/// Single files are returned as is, multi-page images as a JSON array of base64 strings.
+fn render_response(rendered: Rendered, name: &str) -> Result<Response, AppError> {
+    #[derive(serde::Serialize)]
+    struct RenderedPages {
+        media_type: &'static str,
+        pages: Vec<String>,
+    }
+
+    let Rendered {
+        format,
+        mut files,
+        warnings,
+    } = rendered;
 
     let mut headers = HeaderMap::new();
+    headers.insert(WARNING_COUNT_HEADER, HeaderValue::from(warnings.len()));
+    for warning in warnings.iter().take(MAX_WARNING_HEADERS) {
+        headers.append(WARNING_HEADER, warning_header_value(warning)?);
+    }
+
+    if files.len() != 1 {
+        let pages = RenderedPages {
+            media_type: format.media_type(),
+            pages: files.iter().map(|file| BASE64.encode(file)).collect(),
+        };
+        return Ok((headers, Json(pages)).into_response());
+    }
+
     headers.insert(
         header::CONTENT_TYPE,
-        "application/pdf"
-            .parse()
-            .map_err(|_| AppError::InternalServerError)?,
+        HeaderValue::from_static(format.media_type()),
     );
     headers.insert(
         header::CONTENT_DISPOSITION,
-        "inline; filename=\"output.pdf\""
+        format!("inline; filename=\"{name}.{}\"", format.extension())
             .parse()
             .map_err(|_| AppError::InternalServerError)?,
     );
 
-    info!("PDF Served");
-    Ok((headers, pdf_buf))
+    Ok((headers, files.pop().unwrap_or_default()).into_response())
+}
+
+/// Header values must be visible ASCII, so anything else is escaped.
+fn warning_header_value(warning: &Diagnostic) -> Result<HeaderValue, AppError> {
+    let summary = diagnostics::summarize(std::slice::from_ref(warning));
+    let mut escaped = String::with_capacity(summary.len());
+    for c in summary.chars() {
+        if c.is_ascii_graphic() || c == ' ' {
+            escaped.push(c);
+        } else {
+            escaped.extend(c.escape_unicode());
+        }
+    }
+    HeaderValue::from_str(&escaped).map_err(|_| AppError::InternalServerError)
+}
+
+/// Lists every registered template together with its data schema.
+#[instrument(skip(registry))]
+pub async fn list_templates_controller(
+    State(registry): State<Arc<TemplateRegistry>>,
+) -> Json<Vec<TemplateDefinition>> {
+    Json(registry.templates().cloned().collect())
+}
+
+/// Readiness probe: fails while a package pinned by a registered template is not available locally.
+///
+/// Neither downloads nor waits for the render pool, so a busy or offline server stays ready as
+/// long as its packages are cached.
+#[instrument(skip(pinned_packages))]
+pub async fn readiness_controller(
+    State(pinned_packages): State<Arc<PinnedPackages>>,
+) -> impl IntoResponse {
+    #[derive(serde::Serialize)]
+    struct Readiness {
+        ready: bool,
+        packages: Vec<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        failures: Vec<PackageFailure>,
+    }
+
+    let packages = pinned_packages
+        .packages()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    // A few `stat` calls, cheap enough for the runtime.
+    let failures = pinned_packages.missing();
+
+    let ready = failures.is_empty();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            packages,
+            failures,
+        }),
+    )
+}
+
+/// Lists the cached packages, least recently used first.
+#[instrument(skip(render_pool, package_storage))]
+pub async fn list_cached_packages_controller(
+    State(render_pool): State<Arc<RenderPool>>,
+    State(package_storage): State<Arc<PackageStorage>>,
+) -> Result<Json<Vec<CachedPackage>>> {
+    let cached = render_pool
+        .run(move || package_storage.cached_packages())
+        .await?
+        .map_err(|error| {
+            tracing::error!("Failed to list the package cache: {error}");
+            AppError::InternalServerError
+        })?;
+    Ok(Json(cached))
+}
+
+/// Removes a package from the cache, e.g. to force a fresh download.
+#[instrument(skip(render_pool, package_storage))]
+pub async fn purge_cached_package_controller(
+    State(render_pool): State<Arc<RenderPool>>,
+    State(package_storage): State<Arc<PackageStorage>>,
+    Path((namespace, name, version)): Path<(String, String, String)>,
+) -> Result<StatusCode> {
+    let spec = format!("@{namespace}/{name}:{version}");
+    let package = spec
+        .parse()
+        .map_err(|error| AppError::InvalidData(format!("{spec}: {error}")))?;
+
+    let purged = render_pool
+        .run(move || package_storage.purge(&package))
+        .await?
+        .map_err(|error| {
+            tracing::error!("Failed to purge {spec}: {error}");
+            AppError::InternalServerError
+        })?;
+    if !purged {
+        return Err(AppError::PackageNotCached(spec).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct CreatePDF {
     pub template_id: String,
+    /// Unused, the source comes from the template. Accepted for clients that still send it.
+    #[serde(default)]
     pub content: String,
-    // pub data: Value,
+    /// Exposed to the template as `sys.inputs.data`.
+    #[serde(default)]
+    pub data: Value,
+    /// Base64-encoded files available to the template by their path, e.g. `logo.png`.
+    #[serde(default)]
+    pub files: HashMap<String, String>,
+    #[serde(flatten)]
+    pub params: RenderParams,
+}
+
+/// Options of a render, in the body of `/` and in the query string of the other render routes.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default)]
+pub struct RenderParams {
+    /// Fail with the warnings instead of rendering when Typst emits any.
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub format: FormatParam,
+    /// Resolution of PNG output, defaults to [`DEFAULT_DPI`].
+    pub dpi: Option<f32>,
+    /// Tenant whose registered fonts are available to the template.
+    pub tenant: Option<String>,
+    /// Fixed time of the render as RFC 3339, for reproducible output.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub timestamp: Option<time::OffsetDateTime>,
+    /// Stable identifier of the PDF.
+    pub ident: Option<String>,
+    /// IANA time zone of `datetime.today()`, e.g. `Europe/Berlin`.
+    pub timezone: Option<String>,
+}
+
+impl RenderParams {
+    /// Options of the render without any input, and the format to render to.
+    fn into_options(self) -> (RenderOptions, OutputFormat) {
+        let format = self.format.with_dpi(self.dpi);
+        let options = RenderOptions {
+            strict: self.strict,
+            tenant: self.tenant,
+            timestamp: self.timestamp,
+            ident: self.ident,
+            timezone: self.timezone,
+            ..Default::default()
+        };
+        (options, format)
+    }
+}
+
+/// Resolution of PNG output when the request does not specify one.
+pub const DEFAULT_DPI: f32 = 144.0;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FormatParam {
+    #[default]
+    Pdf,
+    Png,
+    Svg,
+}
+
+impl FormatParam {
+    fn with_dpi(self, dpi: Option<f32>) -> OutputFormat {
+        match self {
+            FormatParam::Pdf => OutputFormat::Pdf,
+            FormatParam::Png => OutputFormat::Png {
+                dpi: dpi.unwrap_or(DEFAULT_DPI),
+            },
+            FormatParam::Svg => OutputFormat::Svg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::Request,
+        routing::{get, post},
+    };
+    use tower::ServiceExt;
+    use typst_pdf_api::TypstWrapperWorld;
+    use typst_pdf_api::render_pool::RenderPoolConfig;
+    use typst_pdf_api::templates::diagnostics::DiagnosticSeverity;
+
+    use super::*;
+
+    /// State of a server with the built-in templates, which doesn't resolve any package upfront.
+    pub(super) fn state() -> AppState {
+        AppState {
+            registry: Arc::new(TemplateRegistry::with_builtin_templates()),
+            render_pool: Arc::new(RenderPool::new(RenderPoolConfig::default())),
+            pinned_packages: Arc::new(PinnedPackages::new(
+                Vec::new(),
+                TypstWrapperWorld::shared_packages(),
+            )),
+            package_storage: TypstWrapperWorld::shared_packages(),
+            tenant_fonts: TypstWrapperWorld::shared_tenant_fonts(),
+            upload_limits: UploadLimits::default(),
+        }
+    }
+
+    /// Sends `request` to `app`, returning the status and the JSON body of the response.
+    pub(super) async fn send(app: Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.oneshot(request).await.expect("request is served");
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body is read");
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn post_invoice(body: &str) -> (StatusCode, Value) {
+        let app = Router::new()
+            .route("/invoices/german", post(german_invoice_controller))
+            .with_state(state());
+        let request = Request::post("/invoices/german")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_owned()))
+            .expect("request is valid");
+        send(app, request).await
+    }
+
+    #[tokio::test]
+    async fn requests_without_data_set_no_inputs() {
+        let mut registry = TemplateRegistry::new();
+        registry.register(TemplateDefinition::new(
+            "inputs",
+            "Fails unless there are no inputs",
+            "#assert.eq(sys.inputs, (:))",
+            Value::Null,
+        ));
+        let app = Router::new()
+            .route("/", get(pdf_generation_controller))
+            .with_state(AppState {
+                registry: Arc::new(registry),
+                ..state()
+            });
+        let request = |body: &str| {
+            Request::get("/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_owned()))
+                .expect("request is valid")
+        };
+
+        let (status, _) = send(app.clone(), request(r#"{ "template_id": "inputs" }"#)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = send(
+            app,
+            request(r#"{ "template_id": "inputs", "data": { "name": "Acme" } }"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+    }
+
+    #[test]
+    fn content_is_optional() {
+        let payload: CreatePDF =
+            serde_json::from_str(r#"{ "template_id": "german-invoice", "data": {} }"#)
+                .expect("payload without content is accepted");
+        assert_eq!(payload.template_id, "german-invoice");
+    }
+
+    #[test]
+    fn render_params_are_read_from_the_body_and_the_query() {
+        let payload: CreatePDF = serde_json::from_str(
+            r#"{ "template_id": "inputs", "strict": true, "format": "png", "dpi": 72 }"#,
+        )
+        .expect("payload is valid");
+        let (options, format) = payload.params.into_options();
+        assert!(options.strict);
+        assert!(matches!(format, OutputFormat::Png { dpi: 72.0 }));
+
+        let uri: axum::http::Uri = "/bundle?entrypoint=doc.typ&strict=true&format=png&dpi=72"
+            .parse()
+            .expect("valid uri");
+        let Query(query) =
+            Query::<upload::BundleQuery>::try_from_uri(&uri).expect("query is valid");
+        let Query(params) = Query::<RenderParams>::try_from_uri(&uri).expect("query is valid");
+        assert_eq!(query.entrypoint, "doc.typ");
+        let (options, format) = params.into_options();
+        assert!(options.strict);
+        assert!(matches!(format, OutputFormat::Png { dpi: 72.0 }));
+    }
+
+    #[test]
+    fn query_timestamps_need_an_encoded_offset() {
+        let query = |query: &str| {
+            let uri: axum::http::Uri = format!("/invoices/german?{query}")
+                .parse()
+                .expect("valid uri");
+            Query::<RenderParams>::try_from_uri(&uri).map(|Query(query)| query.timestamp)
+        };
+
+        let expected = time::macros::datetime!(2024-02-29 10:00 +01:00);
+        assert_eq!(
+            query("timestamp=2024-02-29T10:00:00%2B01:00").expect("offset is parsed"),
+            Some(expected)
+        );
+        assert_eq!(
+            query("timestamp=2024-02-29T09:00:00Z").expect("UTC is parsed"),
+            Some(expected)
+        );
+        assert!(
+            query("timestamp=2024-02-29T10:00:00+01:00").is_err(),
+            "`+` decodes to a space"
+        );
+    }
+
+    #[test]
+    fn warning_headers_are_capped() {
+        let warning = |index: usize| Diagnostic {
+            severity: DiagnosticSeverity::Warning,
+            message: format!("warning {index}"),
+            hints: Vec::new(),
+            trace: Vec::new(),
+            location: None,
+        };
+        let rendered = Rendered {
+            format: OutputFormat::Pdf,
+            files: vec![b"%PDF".to_vec()],
+            warnings: (0..MAX_WARNING_HEADERS + 5).map(warning).collect(),
+        };
+
+        let response = render_response(rendered, "output").expect("response is built");
+        let headers = response.headers();
+        assert_eq!(
+            headers[WARNING_COUNT_HEADER],
+            (MAX_WARNING_HEADERS + 5).to_string()
+        );
+        let warnings: Vec<_> = headers.get_all(WARNING_HEADER).iter().collect();
+        assert_eq!(warnings.len(), MAX_WARNING_HEADERS);
+        assert_eq!(warnings[0], "warning 0");
+    }
+
+    #[tokio::test]
+    async fn malformed_invoices_report_the_invalid_field() {
+        let (status, body) =
+            post_invoice(r#"{"invoice_number": "1", "items": [{"description": 1}]}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["errors"][0]["field"], "items[0].description");
+
+        let (status, body) = post_invoice(r#"{"invoice_number": "1"}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["errors"][0]["field"], "date");
+        assert_eq!(body["errors"][0]["message"], "missing field `date`");
+
+        let (status, body) = post_invoice("{").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].is_string(), "{body}");
+    }
 }
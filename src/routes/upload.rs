@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{
+        Multipart, Query, State,
+        multipart::{Field, MultipartError},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Result},
+};
+use tracing::{info, instrument};
+use typst_pdf_api::packages::ExtractionLimits;
+use typst_pdf_api::render_pool::RenderPool;
+use typst_pdf_api::templates::{AppError, RenderOptions, bundle, is_font_file};
+
+use super::{RenderParams, render_response};
+
+/// Main file of an upload without a `main` part.
+const DEFAULT_MAIN: &str = "main.typ";
+
+/// Size limits of uploads to [`upload_controller`].
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimits {
+    /// Maximum size of a single part in bytes.
+    pub max_part_size: usize,
+    /// Maximum number of parts.
+    pub max_parts: usize,
+    /// Maximum size of the whole request body in bytes.
+    pub max_total_size: usize,
+    /// Maximum size of the files of a bundle in bytes, once decompressed.
+    pub max_unpacked_size: usize,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self {
+            max_part_size: 10 * 1024 * 1024,
+            max_parts: 64,
+            max_total_size: 50 * 1024 * 1024,
+            max_unpacked_size: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl UploadLimits {
+    /// Reads `UPLOAD_MAX_PART_SIZE`, `UPLOAD_MAX_PARTS`, `UPLOAD_MAX_TOTAL_SIZE` and
+    /// `UPLOAD_MAX_UNPACKED_SIZE`, falling back to the defaults for unset or unparsable values.
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<usize> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+
+        let default = Self::default();
+        Self {
+            max_part_size: var("UPLOAD_MAX_PART_SIZE").unwrap_or(default.max_part_size),
+            max_parts: var("UPLOAD_MAX_PARTS").unwrap_or(default.max_parts),
+            max_total_size: var("UPLOAD_MAX_TOTAL_SIZE").unwrap_or(default.max_total_size),
+            max_unpacked_size: var("UPLOAD_MAX_UNPACKED_SIZE").unwrap_or(default.max_unpacked_size),
+        }
+    }
+
+    /// Limits of a bundle for [`bundle_controller`], which may hold as many files as an upload
+    /// has parts.
+    fn bundle_limits(&self) -> ExtractionLimits {
+        ExtractionLimits {
+            max_decompressed_size: self.max_unpacked_size,
+            max_entries: self.max_parts,
+        }
+    }
+}
+
+/// Renders a project uploaded as `multipart/form-data`, without access to the server's files.
+///
+/// Parts with a file name are mounted at that path, or loaded as fonts. The optional `data` part
+/// is JSON exposed as `sys.inputs.data`, and the optional `main` part names the file to compile.
+#[instrument(skip(render_pool, multipart))]
+pub async fn upload_controller(
+    State(render_pool): State<Arc<RenderPool>>,
+    State(limits): State<UploadLimits>,
+    Query(params): Query<RenderParams>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse> {
+    info!("Serving upload");
+    let (options, format) = params.into_options();
+    let mut options = RenderOptions {
+        isolated: true,
+        ..options
+    };
+    let mut main = DEFAULT_MAIN.to_owned();
+
+    let mut parts = 0;
+    while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+        parts += 1;
+        if parts > limits.max_parts {
+            return Err(
+                AppError::PayloadTooLarge(format!("more than {} parts", limits.max_parts)).into(),
+            );
+        }
+
+        let name = field.name().unwrap_or_default().to_owned();
+        let file_name = field.file_name().map(ToOwned::to_owned);
+        let part = file_name.as_deref().unwrap_or(&name).to_owned();
+        let content = read_part(field, &part, limits.max_part_size).await?;
+        match (file_name, name.as_str()) {
+            (Some(path), _) if is_font_file(&path) => options.fonts.push((path, content)),
+            (Some(path), _) => {
+                options.files.insert(path, content);
+            }
+            (None, "data") => {
+                let data = serde_json::from_slice(&content)
+                    .map_err(|error| AppError::InvalidData(format!("data: {error}")))?;
+                options.data = Some(data);
+            }
+            (None, "main") => {
+                main = String::from_utf8(content)
+                    .map_err(|_| AppError::InvalidData("main must be a file name".to_owned()))?;
+            }
+            (None, other) => {
+                return Err(AppError::InvalidData(format!("unexpected part `{other}`")).into());
+            }
+        }
+    }
+
+    let source = options.take_main(&main)?;
+
+    let rendered = render_pool
+        .run(move || typst_pdf_api::templates::render_template(source, options, format))
+        .await??;
+
+    info!("Upload served");
+    Ok(render_response(rendered, "output")?)
+}
+
+/// Query string of [`bundle_controller`] besides its [`RenderParams`].
+#[derive(serde::Deserialize, Debug)]
+pub struct BundleQuery {
+    /// Path of the file to compile in the bundle, `main.typ` if not set.
+    #[serde(default = "default_entrypoint")]
+    pub entrypoint: String,
+}
+
+fn default_entrypoint() -> String {
+    DEFAULT_MAIN.to_owned()
+}
+
+/// Renders a project uploaded as a tar.gz or zip archive, without access to the server's files.
+///
+/// The archive is the request body and `?entrypoint=` names the file to compile.
+#[instrument(skip(render_pool, body))]
+pub async fn bundle_controller(
+    State(render_pool): State<Arc<RenderPool>>,
+    State(limits): State<UploadLimits>,
+    Query(query): Query<BundleQuery>,
+    Query(params): Query<RenderParams>,
+    body: Bytes,
+) -> Result<impl IntoResponse> {
+    info!("Serving bundle");
+    let (options, format) = params.into_options();
+    let bundle_limits = limits.bundle_limits();
+    let rendered = render_pool
+        .run(move || {
+            bundle::render_bundle(&body, &query.entrypoint, &bundle_limits, options, format)
+        })
+        .await??;
+
+    info!("Bundle served");
+    Ok(render_response(rendered, "output")?)
+}
+
+/// Reads a part, failing as soon as it exceeds `max_size` bytes.
+async fn read_part(mut field: Field<'_>, name: &str, max_size: usize) -> Result<Vec<u8>, AppError> {
+    let mut content = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+        if content.len() + chunk.len() > max_size {
+            return Err(AppError::PayloadTooLarge(format!(
+                "part `{name}` exceeds {max_size} bytes"
+            )));
+        }
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content)
+}
+
+fn multipart_error(error: MultipartError) -> AppError {
+    if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        AppError::PayloadTooLarge(error.body_text())
+    } else {
+        AppError::InvalidData(error.body_text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, header},
+        routing::post,
+    };
+    use serde_json::Value;
+
+    use super::*;
+    use crate::routes::AppState;
+    use crate::routes::tests::{send, state};
+
+    const BOUNDARY: &str = "upload-test-boundary";
+
+    /// Posts a `multipart/form-data` body of `(name, file name, content)` parts to `/render`.
+    async fn upload(
+        parts: &[(&str, Option<&str>, &[u8])],
+        limits: UploadLimits,
+    ) -> (StatusCode, Value) {
+        let mut body = Vec::new();
+        for (name, file_name, content) in parts {
+            body.extend(
+                format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"").bytes(),
+            );
+            if let Some(file_name) = file_name {
+                body.extend(format!("; filename=\"{file_name}\"").bytes());
+            }
+            body.extend(b"\r\n\r\n");
+            body.extend(*content);
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{BOUNDARY}--\r\n").bytes());
+
+        let app = Router::new()
+            .route("/render", post(upload_controller))
+            .with_state(AppState {
+                upload_limits: limits,
+                ..state()
+            });
+        let request = Request::post("/render")
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .body(Body::from(body))
+            .expect("request is valid");
+        send(app, request).await
+    }
+
+    fn message(body: &Value) -> &str {
+        body["message"].as_str().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn main_part_selects_the_file_to_compile() {
+        let report = ("report.typ", Some("report.typ"), b"= Report".as_slice());
+
+        let (status, _) =
+            upload(&[report, ("main", None, b"report.typ")], Default::default()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = upload(&[report], Default::default()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message(&body).contains("main.typ"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn oversized_parts_are_rejected() {
+        let limits = UploadLimits {
+            max_part_size: 16,
+            ..Default::default()
+        };
+        let (status, body) = upload(&[("main.typ", Some("main.typ"), &[b'a'; 17])], limits).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(message(&body).contains("main.typ"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn too_many_parts_are_rejected() {
+        let limits = UploadLimits {
+            max_parts: 2,
+            ..Default::default()
+        };
+        let main = ("main.typ", Some("main.typ"), b"#read(\"a.txt\")".as_slice());
+        let file = ("a.txt", Some("a.txt"), b"a".as_slice());
+        let (status, _) = upload(&[main, file], limits).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = upload(&[main, file, ("b.txt", Some("b.txt"), b"b")], limits).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(message(&body).contains("more than 2 parts"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn invalid_data_is_rejected() {
+        let main = (
+            "main.typ",
+            Some("main.typ"),
+            b"#sys.inputs.data.name".as_slice(),
+        );
+        let (status, body) =
+            upload(&[main, ("data", None, b"{\"name\":")], Default::default()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message(&body).contains("data:"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn unexpected_parts_are_rejected() {
+        let main = ("main.typ", Some("main.typ"), b"= Hello".as_slice());
+        let (status, body) = upload(&[main, ("other", None, b"value")], Default::default()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message(&body).contains("unexpected part `other`"), "{body}");
+    }
+}
@@ -0,0 +1,333 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use typst::diag::eco_format;
+use zip::ZipArchive;
+
+use super::{AppError, OutputFormat, RenderOptions, Rendered, is_font_file, render_template};
+use crate::packages::{ExtractionLimits, LimitedReader, is_contained, visit_tar_entries};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Signature of the first local header of a zip archive.
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+/// Signature of the end of central directory record, which starts empty zip archives.
+const ZIP_EMPTY_MAGIC: [u8; 4] = *b"PK\x05\x06";
+
+/// Compiles `entrypoint` of a project bundled as a tar.gz or zip archive, without access to the
+/// server's files.
+///
+/// Font files in the bundle are loaded as fonts, all other files are mounted at their path in the
+/// archive, on top of the [files](RenderOptions::files) already in `options`.
+pub fn render_bundle(
+    archive: &[u8],
+    entrypoint: &str,
+    limits: &ExtractionLimits,
+    mut options: RenderOptions,
+    format: OutputFormat,
+) -> Result<Rendered, AppError> {
+    for (path, content) in unpack_bundle(archive, limits)? {
+        if is_font_file(&path) {
+            options.fonts.push((path, content));
+        } else {
+            options.files.insert(path, content);
+        }
+    }
+
+    let source = options.take_main(entrypoint)?;
+    options.isolated = true;
+    render_template(source, options, format)
+}
+
+/// Reads the files of a tar.gz or zip archive into memory, keyed by their path in the archive.
+///
+/// Like packages, bundles may only contain regular files and directories inside the archive, and
+/// must stay within `limits`.
+pub fn unpack_bundle(
+    archive: &[u8],
+    limits: &ExtractionLimits,
+) -> Result<HashMap<String, Vec<u8>>, AppError> {
+    if archive.starts_with(&GZIP_MAGIC) {
+        unpack_tar_gz(archive, limits)
+    } else if archive.starts_with(&ZIP_MAGIC) || archive.starts_with(&ZIP_EMPTY_MAGIC) {
+        unpack_zip(archive, limits)
+    } else {
+        Err(malformed("expected a tar.gz or zip archive"))
+    }
+}
+
+fn unpack_tar_gz(
+    archive: &[u8],
+    limits: &ExtractionLimits,
+) -> Result<HashMap<String, Vec<u8>>, AppError> {
+    let mut limited = LimitedReader::new(GzDecoder::new(archive), limits.max_decompressed_size);
+    let mut files = HashMap::new();
+    let result = visit_tar_entries(&mut limited, limits, "bundle", |path, mut entry| {
+        if entry.header().entry_type().is_dir() {
+            return Ok(());
+        }
+        let name = path
+            .to_str()
+            .ok_or_else(|| eco_format!("path {} is not UTF-8", path.display()))?
+            .to_owned();
+        let mut content = Vec::new();
+        entry
+            .read_to_end(&mut content)
+            .map_err(|error| eco_format!("failed to read {name}: {error}"))?;
+        files.insert(name, content);
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => Ok(files),
+        Err(_) if limited.exceeded() => Err(too_large(limits)),
+        Err(error) => Err(malformed(&error)),
+    }
+}
+
+/// Reads a zip archive through its central directory.
+///
+/// Only stored and deflated entries are supported, encrypted entries are rejected.
+fn unpack_zip(
+    archive: &[u8],
+    limits: &ExtractionLimits,
+) -> Result<HashMap<String, Vec<u8>>, AppError> {
+    let mut zip = ZipArchive::new(Cursor::new(archive))
+        .map_err(|error| malformed(&format!("invalid zip archive: {error}")))?;
+    if zip.len() > limits.max_entries {
+        return Err(malformed(&format!(
+            "more than {} entries",
+            limits.max_entries
+        )));
+    }
+
+    let mut files = HashMap::new();
+    let mut remaining = limits.max_decompressed_size;
+    for index in 0..zip.len() {
+        let Some(Ok(name)) = zip
+            .name_for_index(index)
+            .map(|name| name.map(Cow::into_owned))
+        else {
+            return Err(malformed("zip entry name is not UTF-8"));
+        };
+        let entry = zip
+            .by_index(index)
+            .map_err(|error| malformed(&format!("failed to read {name}: {error}")))?;
+
+        if entry.is_symlink() {
+            return Err(malformed(&format!("link at {name} is not allowed")));
+        }
+        if !is_contained(Path::new(&name)) {
+            return Err(malformed(&format!("path {name} escapes the bundle")));
+        }
+        if entry.is_dir() {
+            continue;
+        }
+
+        // The entry verifies its CRC-32 once it is read to the end.
+        let mut limited = LimitedReader::new(entry, remaining);
+        let mut content = Vec::new();
+        if let Err(error) = limited.read_to_end(&mut content) {
+            return Err(if limited.exceeded() {
+                too_large(limits)
+            } else {
+                malformed(&format!("failed to read {name}: {error}"))
+            });
+        }
+        remaining -= content.len();
+        files.insert(name, content);
+    }
+
+    Ok(files)
+}
+
+fn malformed(message: &str) -> AppError {
+    AppError::InvalidData(format!("bundle: {message}"))
+}
+
+fn too_large(limits: &ExtractionLimits) -> AppError {
+    AppError::PayloadTooLarge(format!(
+        "bundle exceeds {} bytes when decompressed",
+        limits.max_decompressed_size
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    use super::*;
+    use crate::test_registry::crafted_archive;
+
+    const MAIN: &[u8] = b"#import \"chapters/intro.typ\": title\n= #title\n#read(\"data.txt\")";
+    const INTRO: &[u8] = b"#let title = [Bundled]";
+
+    fn limits() -> ExtractionLimits {
+        ExtractionLimits {
+            max_decompressed_size: 1024 * 1024,
+            max_entries: 8,
+        }
+    }
+
+    fn tar_gz(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|&(path, content)| (path, tar::EntryType::Regular, content))
+            .collect();
+        crafted_archive(&entries)
+    }
+
+    /// Builds a zip archive with deflated entries.
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        zip_with(entries, CompressionMethod::Deflated)
+    }
+
+    /// Builds a zip archive with entries compressed by `method`.
+    fn zip_with(entries: &[(&str, &[u8])], method: CompressionMethod) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default()
+            .compression_method(method)
+            .unix_permissions(0o644);
+        for (path, content) in entries {
+            writer.start_file(*path, options).expect("entry is added");
+            writer.write_all(content).expect("entry is written");
+        }
+        writer.finish().expect("archive is finished").into_inner()
+    }
+
+    fn project() -> [(&'static str, &'static [u8]); 3] {
+        [
+            ("report/main.typ", MAIN),
+            ("report/chapters/intro.typ", INTRO),
+            ("report/data.txt", b"from the bundle"),
+        ]
+    }
+
+    fn assert_renders(archive: &[u8]) {
+        let rendered = render_bundle(
+            archive,
+            "report/main.typ",
+            &limits(),
+            RenderOptions::default(),
+            OutputFormat::Svg,
+        )
+        .expect("bundle is rendered");
+
+        let svg = String::from_utf8(rendered.files[0].clone()).expect("svg is UTF-8");
+        assert!(svg.contains("<svg"), "{svg}");
+        assert!(rendered.warnings.is_empty(), "{:?}", rendered.warnings);
+    }
+
+    #[test]
+    fn tar_gz_bundles_are_rendered() {
+        assert_renders(&tar_gz(&project()));
+    }
+
+    #[test]
+    fn zip_bundles_are_rendered() {
+        let archive = zip(&project());
+        let files = unpack_bundle(&archive, &limits()).expect("zip is unpacked");
+        assert_eq!(files["report/chapters/intro.typ"], INTRO);
+
+        assert_renders(&archive);
+    }
+
+    #[test]
+    fn missing_entrypoints_are_rejected() {
+        let error = render_bundle(
+            &zip(&project()),
+            "main.typ",
+            &limits(),
+            RenderOptions::default(),
+            OutputFormat::Pdf,
+        )
+        .expect_err("entrypoint is missing");
+        assert!(
+            matches!(&error, AppError::InvalidData(message) if message.contains("main.typ")),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn paths_escaping_the_bundle_are_rejected() {
+        for archive in [
+            tar_gz(&[("main.typ", MAIN), ("../secret.typ", INTRO)]),
+            zip(&[("main.typ", MAIN), ("../secret.typ", INTRO)]),
+            zip(&[("/etc/secret.typ", INTRO)]),
+        ] {
+            let error = unpack_bundle(&archive, &limits()).expect_err("path escapes");
+            assert!(
+                matches!(&error, AppError::InvalidData(message) if message.contains("escapes the bundle")),
+                "{error:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_bundles_are_rejected() {
+        let zeros = vec![0; 2 * 1024 * 1024];
+        for archive in [
+            tar_gz(&[("zeros.bin", &zeros)]),
+            zip(&[("zeros.bin", &zeros)]),
+        ] {
+            let error = unpack_bundle(&archive, &limits()).expect_err("bundle is too large");
+            assert!(matches!(error, AppError::PayloadTooLarge(_)), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn corrupted_zip_entries_are_rejected() {
+        // Stored, so that the content can be altered without breaking the compression.
+        let mut archive = zip_with(
+            &[("main.typ", MAIN), ("data.txt", b"stored")],
+            CompressionMethod::Stored,
+        );
+        let content = archive
+            .windows(MAIN.len())
+            .position(|window| window == MAIN)
+            .expect("content is stored as is");
+        archive[content] ^= 0x01;
+
+        let error = unpack_bundle(&archive, &limits()).expect_err("entry is corrupted");
+        assert!(
+            matches!(&error, AppError::InvalidData(message)
+                if message.contains("main.typ") && message.contains("checksum")),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn zip_links_are_rejected() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_symlink("secret.typ", "/etc/passwd", SimpleFileOptions::default())
+            .expect("link is added");
+        let archive = writer.finish().expect("archive is finished").into_inner();
+
+        let error = unpack_bundle(&archive, &limits()).expect_err("links are not allowed");
+        assert!(
+            matches!(&error, AppError::InvalidData(message) if message.contains("link at secret.typ")),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn truncated_zip_archives_are_rejected() {
+        let archive = zip(&project());
+        for length in [archive.len() / 2, archive.len() - 10] {
+            let error = unpack_bundle(&archive[..length], &limits()).expect_err("archive is cut");
+            assert!(matches!(error, AppError::InvalidData(_)), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn other_formats_are_rejected() {
+        let error = unpack_bundle(b"= Not an archive", &limits()).expect_err("not an archive");
+        assert!(matches!(error, AppError::InvalidData(_)), "{error:?}");
+    }
+}
@@ -0,0 +1,98 @@
+use serde::Serialize;
+use typst::{
+    World, WorldExt,
+    diag::{Severity, SourceDiagnostic},
+    syntax::Span,
+};
+
+/// A Typst diagnostic with its span resolved to a file and position.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+    pub hints: Vec<String>,
+    /// Function calls, show rules and imports leading to the problem, innermost first.
+    pub trace: Vec<TraceEntry>,
+    #[serde(flatten)]
+    pub location: Option<Location>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceEntry {
+    pub message: String,
+    #[serde(flatten)]
+    pub location: Option<Location>,
+}
+
+/// Position of a span in a source file. Lines and columns are 1-based.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    /// Path of the file, prefixed with the package spec for package files.
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Diagnostic {
+    pub fn from_source_diagnostic(world: &dyn World, diagnostic: &SourceDiagnostic) -> Self {
+        Self {
+            severity: match diagnostic.severity {
+                Severity::Error => DiagnosticSeverity::Error,
+                Severity::Warning => DiagnosticSeverity::Warning,
+            },
+            message: diagnostic.message.to_string(),
+            hints: diagnostic.hints.iter().map(ToString::to_string).collect(),
+            trace: diagnostic
+                .trace
+                .iter()
+                .map(|point| TraceEntry {
+                    message: point.v.to_string(),
+                    location: Location::resolve(world, point.span),
+                })
+                .collect(),
+            location: Location::resolve(world, diagnostic.span),
+        }
+    }
+}
+
+impl Location {
+    /// Returns `None` for detached spans, which do not point into any file.
+    fn resolve(world: &dyn World, span: Span) -> Option<Self> {
+        let id = span.id()?;
+        let source = world.source(id).ok()?;
+        let start = world.range(span)?.start;
+
+        let path = id.vpath().as_rootless_path().display();
+        let file = match id.package() {
+            Some(package) => format!("{package}/{path}"),
+            None => path.to_string(),
+        };
+
+        Some(Self {
+            file,
+            line: source.byte_to_line(start)? + 1,
+            column: source.byte_to_column(start)? + 1,
+        })
+    }
+}
+
+/// Joins the messages of `diagnostics` into a single line, for logs and `Display`.
+pub fn summarize(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| match &diagnostic.location {
+            Some(Location { file, line, column }) => {
+                format!("{file}:{line}:{column}: {}", diagnostic.message)
+            }
+            None => diagnostic.message.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
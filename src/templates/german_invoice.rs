@@ -1,5 +1,82 @@
+use std::collections::HashMap;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{AppError, validation::Validator};
+
+pub const TEMPLATE_ID: &str = "german-invoice";
+
 pub const GERMAN_INVOICE_TEMPLATE: &str = include_str!("../../templates/german_invoice.typ");
 
+/// Path that [`GERMAN_INVOICE_TEMPLATE`] reads the author's signature image from.
+pub const SIGNATURE_PATH: &str = "author-signature";
+
+/// JSON schema of [`GermanTemplateData`].
+pub fn schema() -> Value {
+    let string = json!({ "type": "string" });
+    let address = json!({
+        "type": "object",
+        "required": ["street", "city", "zip_code", "country", "tax_nb"],
+        "properties": {
+            "street": string,
+            "city": string,
+            "zip_code": string,
+            "country": string,
+            "tax_nb": string,
+            "signature": { "type": ["string", "null"], "contentEncoding": "base64" },
+        },
+    });
+
+    json!({
+        "type": "object",
+        "required": [
+            "invoice_number", "date", "items", "author", "recipient",
+            "bank_account", "vat_rate", "is_micro_business",
+        ],
+        "properties": {
+            "invoice_number": string,
+            "date": { "type": "string", "format": "date" },
+            "items": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["description", "price"],
+                    "properties": {
+                        "description": string,
+                        "price": { "type": "number" },
+                    },
+                },
+            },
+            "author": {
+                "type": "object",
+                "required": ["name", "address", "email"],
+                "properties": { "name": string, "address": address, "email": string },
+            },
+            "recipient": {
+                "type": "object",
+                "required": ["name", "address"],
+                "properties": { "name": string, "address": address },
+            },
+            "bank_account": {
+                "type": "object",
+                "required": ["name", "iban", "bic", "bank_name", "gender"],
+                "properties": {
+                    "name": string,
+                    "iban": string,
+                    "bic": string,
+                    "bank_name": string,
+                    "gender": string,
+                },
+            },
+            "vat_rate": { "type": "number" },
+            "is_micro_business": { "type": "boolean" },
+        },
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GermanTemplateData {
     pub invoice_number: String,
     /// YYYY-MM-DD format
@@ -8,82 +85,87 @@ pub struct GermanTemplateData {
     pub author: Author,
     pub recipient: Client,
     pub bank_account: BankAccount,
+    /// VAT rate in percent, e.g. `19.0`
     pub vat_rate: f32,
-    /// Whether the invoice is for a micro business or kleinunternehmer
+    /// Whether the invoice is for a micro business or kleinunternehmer.
+    /// Micro businesses charge no VAT and print the §19 UStG notice instead.
     pub is_micro_business: bool,
 }
 
 impl GermanTemplateData {
-    pub fn into_typst_template(self) -> String {
-        let GermanTemplateData {
-            invoice_number,
-            date,
-            items,
-            author,
-            recipient,
-            bank_account,
-            vat_rate: _vat_rate,
-            is_micro_business: _is_micro_business,
-        } = self;
-
-        let items_str: String = items
-            .into_iter()
-            .map(InvoiceItem::into_pdf_params)
-            .collect::<Vec<_>>()
-            .join(",\n    ");
-
-        let author_str = author.into_pdf_params();
-
-        let client_str = recipient.into_pdf_params();
-
-        let bank_account_str = bank_account.into_pdf_params();
-
-        let date_str = Self::date_to_typst_datetime(&date);
-
-        format!(
-            r#"
-#import "@preview/classy-german-invoice:0.3.1": invoice
-
-#show: invoice(
-  "{}",
-  // Invoice date
-  {},
-  // Items
-  (
-    {}
-  ),
-  // Author
-    {},
-  // Recipient
-    {},
-  // Bank account
-    {},
-  // Umsatzsteuersatz (VAT)
-  vat: 0.19,
-  kleinunternehmer: true,
-  )
-        "#,
-            invoice_number, date_str, items_str, author_str, client_str, bank_account_str
-        )
+    /// Converts the invoice into the JSON payload read by [`GERMAN_INVOICE_TEMPLATE`].
+    ///
+    /// This does not include the signature image, see [`Self::into_template_input`].
+    pub fn into_template_data(self) -> Value {
+        serde_json::to_value(self).expect("invoice data is always representable as JSON")
     }
 
-    /// Converts a YYYY-MM-DD date string to Typst datetime format
-    fn date_to_typst_datetime(date_str: &str) -> String {
-        // Parse YYYY-MM-DD format
-        let parts: Vec<&str> = date_str.split('-').collect();
-        if parts.len() != 3 {
-            // Fallback to a default date if parsing fails
-            return "datetime(year: 2023, month: 1, day: 1)".to_string();
+    /// Converts the invoice into the JSON payload and the files read by
+    /// [`GERMAN_INVOICE_TEMPLATE`], decoding the author's signature into the file at
+    /// [`SIGNATURE_PATH`].
+    pub fn into_template_input(mut self) -> Result<(Value, HashMap<String, Vec<u8>>), AppError> {
+        let mut files = HashMap::new();
+        if let Some(signature) = &self.author.address.signature {
+            let image = BASE64.decode(signature).map_err(|error| {
+                AppError::InvalidData(format!("author.address.signature: {error}"))
+            })?;
+            files.insert(SIGNATURE_PATH.to_owned(), image);
+            // The template only needs to know that there is one.
+            self.author.address.signature = Some(SIGNATURE_PATH.to_owned());
         }
+        Ok((self.into_template_data(), files))
+    }
 
-        let year = parts[0].parse::<u32>().unwrap_or(2023);
-        let month = parts[1].parse::<u32>().unwrap_or(1);
-        let day = parts[2].parse::<u32>().unwrap_or(1);
+    /// Checks every field that would otherwise only fail during Typst compilation.
+    pub fn validate(&self) -> Result<(), AppError> {
+        let mut validator = Validator::new();
 
-        format!("datetime(year: {}, month: {}, day: {})", year, month, day)
+        validator.non_empty("invoice_number", &self.invoice_number);
+        validator.date("date", &self.date);
+
+        if self.items.is_empty() {
+            validator.error("items", "must contain at least one item");
+        }
+        for (index, item) in self.items.iter().enumerate() {
+            validator.non_empty(&format!("items[{index}].description"), &item.description);
+            if !item.price.is_finite() {
+                validator.error(format!("items[{index}].price"), "must be a finite number");
+            }
+        }
+
+        validator.non_empty("author.name", &self.author.name);
+        validator.non_empty("author.email", &self.author.email);
+        self.author
+            .address
+            .validate("author.address", &mut validator);
+        if let Some(signature) = &self.author.address.signature
+            && BASE64.decode(signature).is_err()
+        {
+            validator.error(
+                "author.address.signature",
+                "must be a base64-encoded PNG, JPEG, GIF or SVG image",
+            );
+        }
+
+        validator.non_empty("recipient.name", &self.recipient.name);
+        self.recipient
+            .address
+            .validate("recipient.address", &mut validator);
+
+        validator.non_empty("bank_account.name", &self.bank_account.name);
+        validator.non_empty("bank_account.bank_name", &self.bank_account.bank_name);
+        validator.iban("bank_account.iban", &self.bank_account.iban);
+        validator.bic("bank_account.bic", &self.bank_account.bic);
+
+        if !(0.0..=100.0).contains(&self.vat_rate) {
+            validator.error("vat_rate", "must be a percentage between 0 and 100");
+        }
+
+        validator.finish()
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BankAccount {
     pub name: String,
     pub iban: String,
@@ -92,134 +174,107 @@ pub struct BankAccount {
     pub gender: String,
 }
 
-impl BankAccount {
-    pub fn into_pdf_params(self) -> String {
-        let BankAccount {
-            iban,
-            bic,
-            bank_name,
-            name,
-            gender: _gender,
-        } = self;
-
-        format!(
-            r#"
-  (
-    name: "{name}",
-    bank: "{bank_name}",
-    iban: "{iban}",
-    bic: "{bic}",
-    // There is currently only one gendered term in this template.
-    // You can overwrite it, or omit it and just choose the default.
-    gender: (account_holder: "Kontoinhaberin")
-    )
-                "#
-        )
-    }
-}
-
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Client {
     pub name: String,
     pub address: Address,
 }
 
-impl Client {
-    pub fn into_pdf_params(self) -> String {
-        let Client {
-            name,
-            address:
-                Address {
-                    street,
-                    city,
-                    zip_code: zip,
-                    country: _country,     // Not used in the template
-                    tax_nb: _tax_nb,       // Not used in the template
-                    signature: _signature, // Not used in the template
-                },
-        } = self;
-
-        format!(
-            r#"(
-              name: "{name}",
-              street: "{street}",
-              zip: "{zip}",
-              city: "{city}",
-            )"#
-        )
-    }
-}
-
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InvoiceItem {
     pub description: String,
     pub price: f64,
 }
 
-impl InvoiceItem {
-    pub fn into_pdf_params(self) -> String {
-        let InvoiceItem { description, price } = self;
-
-        format!(
-            r#"(
-              description: "{description}",
-              price: {price},
-            )"#
-        )
-    }
-}
-
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Author {
     pub name: String,
     pub address: Address,
     pub email: String,
 }
 
-impl Author {
-    pub fn into_pdf_params(self) -> String {
-        let Author {
-            name,
-            address:
-                Address {
-                    street,
-                    city,
-                    zip_code: zip,
-                    country: _country, // Not used in the template
-                    tax_nb,
-                    // TODO: optional signature
-                    signature: _signature,
-                },
-            email: _email, // Not used in the template
-        } = self;
-
-        format!(
-            r#"(
-                  name: "{name}",
-                  street: "{street}",
-                  zip: "{zip}",
-                  city: "{city}",
-                  tax_nr: "{tax_nb}",
-                  // optional signature, can be omitted
-                  // signature: image("example_signature.png", width: 5em)
-                )"#
-        )
-    }
-}
-
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Address {
     pub street: String,
     pub city: String,
     pub zip_code: String,
     pub country: String,
     pub tax_nb: String,
+    /// Base64-encoded signature image (PNG, JPEG, GIF or SVG), only printed for the author.
+    #[serde(default)]
     pub signature: Option<String>,
 }
 
+impl Address {
+    fn validate(&self, prefix: &str, validator: &mut Validator) {
+        validator.non_empty(&format!("{prefix}.street"), &self.street);
+        validator.non_empty(&format!("{prefix}.city"), &self.city);
+        validator.non_empty(&format!("{prefix}.zip_code"), &self.zip_code);
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::templates::template_to_pdf;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use typst::foundations::{self, Dict, Label};
+    use typst::introspection::MetadataElem;
+    use typst::layout::PagedDocument;
+    use typst::utils::PicoStr;
+
+    use crate::TypstWrapperWorld;
+    use crate::packages::{PackageConfig, PackageStorage};
+    use crate::templates::{RenderOptions, data_to_inputs, template_to_pdf_with_options};
 
     use super::*;
 
+    /// The published package imported by [`GERMAN_INVOICE_TEMPLATE`].
+    const PACKAGE: &str = "@preview/classy-german-invoice:0.3.1";
+
+    /// Stub in `tests/packages` that records the arguments of `invoice` instead of rendering an
+    /// invoice, so that the mapping of the invoice data can be tested without network access.
+    const STUB_PACKAGE: &str = "@test-stub/classy-german-invoice:0.3.1";
+
+    /// [`GERMAN_INVOICE_TEMPLATE`], importing [`STUB_PACKAGE`] instead of the published package.
+    fn stub_template() -> String {
+        assert!(GERMAN_INVOICE_TEMPLATE.contains(PACKAGE));
+        GERMAN_INVOICE_TEMPLATE.replace(PACKAGE, STUB_PACKAGE)
+    }
+
+    fn stub_packages() -> Arc<PackageStorage> {
+        let package_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/packages");
+        Arc::new(PackageStorage::new(PackageConfig {
+            package_path: Some(package_path),
+            offline: true,
+            ..Default::default()
+        }))
+    }
+
+    /// The arguments the template passes to the package's `invoice` for `data`.
+    fn invoice_arguments(data: GermanTemplateData) -> Dict {
+        let inputs = data_to_inputs(data.into_template_data()).expect("data is valid");
+        let world = TypstWrapperWorld::with_source(stub_template())
+            .with_packages(stub_packages())
+            .with_inputs(inputs);
+        let document = typst::compile::<PagedDocument>(&world)
+            .output
+            .expect("Failed to compile template");
+        let recorded = document
+            .introspector
+            .query_label(Label::new(PicoStr::intern("invoice-arguments")))
+            .expect("stub records its arguments");
+        let metadata = recorded
+            .to_packed::<MetadataElem>()
+            .expect("arguments are recorded as metadata");
+        metadata
+            .value
+            .clone()
+            .cast()
+            .expect("arguments are a dictionary")
+    }
+
     impl GermanTemplateData {
         pub fn fake() -> Self {
             GermanTemplateData {
@@ -273,8 +328,185 @@ mod tests {
 
     #[test]
     fn compile_german_data_into_german_invoice_pdf() {
-        let data = GermanTemplateData::fake();
-        let template = data.into_typst_template();
-        let _pdf = template_to_pdf(template).expect("Failed to compile template");
+        let options = RenderOptions {
+            data: Some(GermanTemplateData::fake().into_template_data()),
+            packages: Some(stub_packages()),
+            ..Default::default()
+        };
+        let _pdf = template_to_pdf_with_options(stub_template(), options)
+            .expect("Failed to compile template");
+    }
+
+    #[test]
+    fn micro_businesses_are_kleinunternehmer() {
+        let arguments = invoice_arguments(GermanTemplateData::fake());
+
+        assert_eq!(
+            arguments.get("kleinunternehmer"),
+            Ok(&foundations::Value::Bool(true))
+        );
+    }
+
+    #[test]
+    fn vat_rate_is_passed_as_a_fraction() {
+        for (vat_rate, vat) in [(19.0, 0.19), (7.0, 0.07)] {
+            let arguments = invoice_arguments(GermanTemplateData {
+                is_micro_business: false,
+                vat_rate,
+                ..GermanTemplateData::fake()
+            });
+
+            assert_eq!(
+                arguments.get("kleinunternehmer"),
+                Ok(&foundations::Value::Bool(false))
+            );
+            assert_eq!(arguments.get("vat"), Ok(&foundations::Value::Float(vat)));
+        }
+    }
+
+    #[test]
+    fn items_are_passed_with_their_prices() {
+        let arguments = invoice_arguments(GermanTemplateData::fake());
+
+        let items: foundations::Array = arguments
+            .get("items")
+            .ok()
+            .and_then(|items| items.clone().cast().ok())
+            .expect("items are an array");
+        let prices: Vec<_> = items
+            .into_iter()
+            .map(|item| {
+                let item: Dict = item.cast().expect("item is a dictionary");
+                item.get("price").cloned().expect("item has a price")
+            })
+            .collect();
+        assert_eq!(
+            prices,
+            [
+                foundations::Value::Float(100.0),
+                foundations::Value::Float(200.0)
+            ]
+        );
+    }
+
+    /// Renders `data` with the published package, which is downloaded from the registry.
+    fn render_text(data: GermanTemplateData) -> String {
+        let options = RenderOptions {
+            data: Some(data.into_template_data()),
+            ..Default::default()
+        };
+        let pdf = template_to_pdf_with_options(GERMAN_INVOICE_TEMPLATE.to_string(), options)
+            .expect("Failed to compile template");
+        pdf_extract::extract_text_from_mem(&pdf).expect("Failed to extract PDF text")
+    }
+
+    #[test]
+    #[ignore = "downloads @preview/classy-german-invoice:0.3.1 from the package registry"]
+    fn micro_business_invoice_has_no_vat() {
+        let text = render_text(GermanTemplateData::fake());
+
+        assert!(
+            text.contains("19 UStG"),
+            "missing §19 UStG notice in:\n{text}"
+        );
+        assert!(text.contains("300"), "missing net total in:\n{text}");
+        assert!(!text.contains("357"), "unexpected VAT total in:\n{text}");
+    }
+
+    #[test]
+    #[ignore = "downloads @preview/classy-german-invoice:0.3.1 from the package registry"]
+    fn regular_invoice_charges_vat() {
+        let text = render_text(GermanTemplateData {
+            is_micro_business: false,
+            ..GermanTemplateData::fake()
+        });
+
+        assert!(
+            !text.contains("19 UStG"),
+            "unexpected §19 UStG notice in:\n{text}"
+        );
+        assert!(text.contains("357"), "missing gross total in:\n{text}");
+    }
+
+    #[test]
+    #[ignore = "downloads @preview/classy-german-invoice:0.3.1 from the package registry"]
+    fn reduced_vat_rate_is_applied() {
+        let text = render_text(GermanTemplateData {
+            is_micro_business: false,
+            vat_rate: 7.0,
+            ..GermanTemplateData::fake()
+        });
+
+        assert!(text.contains("321"), "missing gross total in:\n{text}");
+        assert!(
+            !text.contains("357"),
+            "unexpected 19% gross total in:\n{text}"
+        );
+    }
+
+    #[test]
+    fn fake_data_is_valid() {
+        GermanTemplateData::fake()
+            .validate()
+            .expect("fake data is valid");
+    }
+
+    #[test]
+    fn validation_reports_each_invalid_field() {
+        let mut data = GermanTemplateData::fake();
+        data.date = "2023-02-30".to_string();
+        data.bank_account.iban = "DE89370400440532013001".to_string();
+        data.items[1].description = " ".to_string();
+
+        let Err(AppError::ValidationError(errors)) = data.validate() else {
+            panic!("invalid data should be rejected");
+        };
+        let fields: Vec<_> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            ["date", "items[1].description", "bank_account.iban"]
+        );
+    }
+
+    #[test]
+    fn signature_is_mounted_as_a_file() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="10"><path d="M0 5 L40 5" stroke="black"/></svg>"#;
+        let mut data = GermanTemplateData::fake();
+        data.author.address.signature = Some(BASE64.encode(svg));
+        data.validate().expect("signature is valid");
+
+        let (data, files) = data.into_template_input().expect("signature decodes");
+        assert_eq!(data["author"]["address"]["signature"], SIGNATURE_PATH);
+        assert_eq!(files[SIGNATURE_PATH], svg.as_bytes());
+
+        let options = RenderOptions {
+            data: Some(data),
+            files,
+            packages: Some(stub_packages()),
+            ..Default::default()
+        };
+        template_to_pdf_with_options(stub_template(), options).expect("signature image is found");
+    }
+
+    #[test]
+    fn signature_must_be_base64() {
+        let mut data = GermanTemplateData::fake();
+        data.author.address.signature = Some("not base64!".to_string());
+
+        let Err(AppError::ValidationError(errors)) = data.validate() else {
+            panic!("invalid signature should be rejected");
+        };
+        assert_eq!(errors[0].field, "author.address.signature");
+    }
+
+    #[test]
+    fn invoice_round_trips_through_json() {
+        let json = serde_json::to_string(&GermanTemplateData::fake()).expect("serialize");
+        let data: GermanTemplateData = serde_json::from_str(&json).expect("deserialize");
+
+        assert_eq!(
+            data.into_template_data(),
+            GermanTemplateData::fake().into_template_data()
+        );
     }
 }
@@ -0,0 +1,31 @@
+use serde_json::{Value, json};
+
+pub const TEMPLATE_ID: &str = "letter";
+
+pub const LETTER_TEMPLATE: &str = include_str!("../../templates/letter.typ");
+
+/// JSON schema of the data accepted by [`LETTER_TEMPLATE`].
+pub fn schema() -> Value {
+    let party = json!({
+        "type": "object",
+        "required": ["name", "street", "zip_code", "city"],
+        "properties": {
+            "name": { "type": "string" },
+            "street": { "type": "string" },
+            "zip_code": { "type": "string" },
+            "city": { "type": "string" },
+        },
+    });
+
+    json!({
+        "type": "object",
+        "required": ["sender", "recipient", "date", "subject", "body"],
+        "properties": {
+            "sender": party,
+            "recipient": party,
+            "date": { "type": "string", "format": "date" },
+            "subject": { "type": "string" },
+            "body": { "type": "string" },
+        },
+    })
+}
@@ -1,62 +1,398 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
 use axum::{
-    extract::FromRequest,
-    http::StatusCode,
+    extract::{FromRequest, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Response},
 };
 use serde::Serialize;
 use thiserror::Error;
 use tracing::instrument;
-use typst::{diag::Warned, layout::PagedDocument};
-use typst_pdf::PdfOptions;
+use typst::{
+    diag::Warned,
+    foundations::{Bytes, Datetime, Dict, Smart, Value},
+    layout::PagedDocument,
+    syntax::VirtualPath,
+    text::Font,
+};
+use typst_pdf::{PdfOptions, Timestamp};
 
 use crate::TypstWrapperWorld;
+use crate::packages::PackageStorage;
+use crate::timezones::parse_timezone;
+use diagnostics::Diagnostic;
+use validation::FieldError;
 
+pub mod bundle;
+pub mod diagnostics;
 pub mod german_invoice;
+pub mod letter;
+pub mod registry;
+pub mod validation;
 
 /// Custom error type for the application
 #[derive(Debug, Error)]
 pub enum AppError {
-    #[error("Failed to compile template: {0}")]
-    CompilationError(String),
+    #[error("Failed to compile template: {}", diagnostics::summarize(.0))]
+    CompilationError(Vec<Diagnostic>),
     #[error("PDF generation error: {0}")]
     PdfGenerationError(String),
+    #[error("Invalid template data: {0}")]
+    InvalidData(String),
+    #[error("Invalid fields: {0:?}")]
+    ValidationError(Vec<FieldError>),
+    #[error("Template not found: {0}")]
+    TemplateNotFound(String),
+    #[error("Package not cached: {0}")]
+    PackageNotCached(String),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Server is overloaded, retry after {retry_after:?}")]
+    Overloaded { retry_after: Duration },
     #[error("Internal server error")]
     InternalServerError,
 }
 
+/// Everything besides the template source that influences a render.
+#[derive(Default)]
+pub struct RenderOptions {
+    /// JSON payload exposed to the template as `sys.inputs.data`.
+    pub data: Option<serde_json::Value>,
+    /// Fail the render if Typst emits any warning, e.g. a missing-font fallback.
+    pub strict: bool,
+    /// Directory that local files like images and includes are resolved in, instead of
+    /// [`crate::default_root`].
+    pub root: Option<PathBuf>,
+    /// Files available to this render only, keyed by their path (e.g. `logo.png`), which take
+    /// precedence over the files in the root.
+    pub files: HashMap<String, Vec<u8>>,
+    /// Font files (TTF, OTF or collections) available to this render only, keyed by file name.
+    pub fonts: Vec<(String, Vec<u8>)>,
+    /// Path of the template source in the project, `main.typ` if not set.
+    pub main: Option<String>,
+    /// Only resolve `files` and packages, never files on disk.
+    pub isolated: bool,
+    /// Tenant whose registered fonts are available in addition to the shared fonts.
+    pub tenant: Option<String>,
+    /// Time of the render for `datetime.today()` and the creation date of PDFs, so that the same
+    /// input always renders the same bytes. PDFs have no creation date if not set.
+    pub timestamp: Option<time::OffsetDateTime>,
+    /// Stable identifier of the PDF, e.g. an invoice number. Derived from the document's title
+    /// and author if not set.
+    pub ident: Option<String>,
+    /// IANA time zone of `datetime.today()`, e.g. `Europe/Berlin`, instead of the tenant's or the
+    /// configured zone.
+    pub timezone: Option<String>,
+    /// Where packages are resolved, instead of [`TypstWrapperWorld::shared_packages`].
+    pub packages: Option<Arc<PackageStorage>>,
+}
+
+/// Lists files and fonts by name and size, rather than printing their content.
+impl fmt::Debug for RenderOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn sizes<'a>(files: impl IntoIterator<Item = (&'a String, &'a Vec<u8>)>) -> Vec<String> {
+            files
+                .into_iter()
+                .map(|(name, content)| format!("{name} ({} bytes)", content.len()))
+                .collect()
+        }
+
+        f.debug_struct("RenderOptions")
+            .field("data", &self.data)
+            .field("strict", &self.strict)
+            .field("root", &self.root)
+            .field("files", &sizes(&self.files))
+            .field(
+                "fonts",
+                &sizes(self.fonts.iter().map(|(name, data)| (name, data))),
+            )
+            .field("main", &self.main)
+            .field("isolated", &self.isolated)
+            .field("tenant", &self.tenant)
+            .field("timestamp", &self.timestamp)
+            .field("ident", &self.ident)
+            .field("timezone", &self.timezone)
+            .field("packages", &self.packages)
+            .finish()
+    }
+}
+
+impl RenderOptions {
+    /// Removes the file at `main` from [`files`](Self::files) and makes it the
+    /// [main file](Self::main), returning its source.
+    pub fn take_main(&mut self, main: &str) -> Result<String, AppError> {
+        let main_path = VirtualPath::new(main);
+        let main_file = self
+            .files
+            .keys()
+            .find(|path| VirtualPath::new(path) == main_path)
+            .cloned()
+            .ok_or_else(|| AppError::InvalidData(format!("main file `{main}` does not exist")))?;
+        let source = self
+            .files
+            .remove(&main_file)
+            .map(String::from_utf8)
+            .and_then(Result::ok)
+            .ok_or_else(|| AppError::InvalidData(format!("main file `{main}` is not UTF-8")))?;
+        self.main = Some(main_file);
+        Ok(source)
+    }
+}
+
+/// Files with these extensions are loaded as fonts when they are part of an uploaded project.
+pub const FONT_EXTENSIONS: [&str; 4] = ["ttf", "otf", "ttc", "otc"];
+
+/// Whether `path` has one of the [`FONT_EXTENSIONS`], ignoring case.
+pub fn is_font_file(path: &str) -> bool {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    extension.is_some_and(|extension| FONT_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Output of a render.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    /// A single PDF file.
+    #[default]
+    Pdf,
+    /// One PNG image per page, rasterized at `dpi` dots per inch.
+    Png { dpi: f32 },
+    /// One SVG image per page.
+    Svg,
+}
+
+impl OutputFormat {
+    /// Highest resolution accepted for PNG output, to bound the size of the rasterized pages.
+    pub const MAX_DPI: f32 = 600.0;
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "application/pdf",
+            OutputFormat::Png { .. } => "image/png",
+            OutputFormat::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Png { .. } => "png",
+            OutputFormat::Svg => "svg",
+        }
+    }
+}
+
+/// A rendered document together with the warnings Typst emitted while compiling it.
+#[derive(Debug)]
+pub struct Rendered {
+    pub format: OutputFormat,
+    /// The PDF file, or one image per page.
+    pub files: Vec<Vec<u8>>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// A rendered PDF together with the warnings Typst emitted while compiling it.
+#[derive(Debug)]
+pub struct RenderedPdf {
+    pub pdf: Vec<u8>,
+    pub warnings: Vec<Diagnostic>,
+}
+
 /// Converts a Typst template string to a PDF byte buffer.
-#[instrument]
 pub fn template_to_pdf(content: String) -> Result<Vec<u8>, AppError> {
+    template_to_pdf_with_options(content, RenderOptions::default())
+}
+
+/// Converts a Typst template string to a PDF byte buffer, using `options` for the render.
+pub fn template_to_pdf_with_options(
+    content: String,
+    options: RenderOptions,
+) -> Result<Vec<u8>, AppError> {
+    template_to_pdf_with_warnings(content, options).map(|rendered| rendered.pdf)
+}
+
+/// Converts a Typst template string to a PDF byte buffer and keeps the compiler warnings.
+///
+/// In [strict](RenderOptions::strict) mode, warnings are returned as a [`AppError::CompilationError`].
+pub fn template_to_pdf_with_warnings(
+    content: String,
+    options: RenderOptions,
+) -> Result<RenderedPdf, AppError> {
+    let Rendered {
+        mut files,
+        warnings,
+        ..
+    } = render_template(content, options, OutputFormat::Pdf)?;
+
+    Ok(RenderedPdf {
+        pdf: files.pop().ok_or(AppError::InternalServerError)?,
+        warnings,
+    })
+}
+
+/// Compiles a Typst template string and exports it as `format`.
+///
+/// In [strict](RenderOptions::strict) mode, warnings are returned as a [`AppError::CompilationError`].
+#[instrument(skip_all, fields(format = ?format))]
+pub fn render_template(
+    content: String,
+    options: RenderOptions,
+    format: OutputFormat,
+) -> Result<Rendered, AppError> {
+    if let OutputFormat::Png { dpi } = format
+        && !(dpi > 0.0 && dpi <= OutputFormat::MAX_DPI)
+    {
+        return Err(AppError::InvalidData(format!(
+            "DPI must be between 0 and {}, got {dpi}",
+            OutputFormat::MAX_DPI
+        )));
+    }
+
     tracing::debug!(
         "Template content to compile:
 {}",
         content
     );
 
-    let world = TypstWrapperWorld::with_source(content);
+    let mut world = TypstWrapperWorld::with_source(content);
+    if let Some(tenant) = &options.tenant {
+        world = world.with_tenant(tenant);
+    }
+    if let Some(timezone) = &options.timezone {
+        world = world.with_timezone(parse_timezone(timezone)?);
+    }
+    if let Some(root) = &options.root {
+        world = world.with_root(root);
+    }
+    if let Some(timestamp) = options.timestamp {
+        world = world.with_time(timestamp);
+    }
+    if let Some(packages) = options.packages {
+        world = world.with_packages(packages);
+    }
+    if options.isolated {
+        world = world.isolated();
+    }
+    if let Some(main) = &options.main {
+        world = world.with_main_path(main);
+    }
+    if !options.files.is_empty() {
+        world = world.with_files(options.files);
+    }
+    if !options.fonts.is_empty() {
+        world = world.with_fonts(load_fonts(options.fonts)?);
+    }
+    if let Some(data) = options.data {
+        world = world.with_inputs(data_to_inputs(data)?);
+    }
 
-    let Warned {
-        output,
-        warnings: _warnings,
-    } = typst::compile::<PagedDocument>(&world);
+    let Warned { output, warnings } = typst::compile::<PagedDocument>(&world);
+    let warnings: Vec<_> = warnings
+        .iter()
+        .map(|warning| Diagnostic::from_source_diagnostic(&world, warning))
+        .collect();
 
     let document = output.map_err(|errors| {
-        let error_msg = errors
+        let diagnostics: Vec<_> = errors
             .iter()
-            .map(|e| format!("{:?}", e))
-            .collect::<Vec<_>>()
-            .join("; ");
-        tracing::error!("Typst compilation errors: {}", error_msg);
-        AppError::CompilationError(error_msg)
+            .map(|error| Diagnostic::from_source_diagnostic(&world, error))
+            .chain(warnings.iter().cloned())
+            .collect();
+        tracing::error!(
+            "Typst compilation errors: {}",
+            diagnostics::summarize(&diagnostics)
+        );
+        AppError::CompilationError(diagnostics)
     })?;
 
-    let pdf_buf = typst_pdf::pdf(&document, &PdfOptions::default()).map_err(|e| {
-        let error_msg = format!("{:?}", e);
-        tracing::error!("PDF generation error: {}", error_msg);
-        AppError::PdfGenerationError(error_msg)
-    })?;
+    if !warnings.is_empty() {
+        let summary = diagnostics::summarize(&warnings);
+        if options.strict {
+            tracing::error!("Typst warnings in strict mode: {}", summary);
+            return Err(AppError::CompilationError(warnings));
+        }
+        tracing::warn!("Typst compilation warnings: {}", summary);
+    }
 
-    Ok(pdf_buf)
+    let files = match format {
+        OutputFormat::Pdf => {
+            let pdf_options = PdfOptions {
+                ident: options.ident.as_deref().map_or(Smart::Auto, Smart::Custom),
+                timestamp: options.timestamp.and_then(pdf_timestamp),
+                ..Default::default()
+            };
+            let pdf_buf = typst_pdf::pdf(&document, &pdf_options).map_err(|e| {
+                let error_msg = format!("{:?}", e);
+                tracing::error!("PDF generation error: {}", error_msg);
+                AppError::PdfGenerationError(error_msg)
+            })?;
+            vec![pdf_buf]
+        }
+        OutputFormat::Png { dpi } => document
+            .pages
+            .iter()
+            .map(|page| {
+                typst_render::render(page, dpi / 72.0)
+                    .encode_png()
+                    .map_err(|e| AppError::PdfGenerationError(format!("PNG encoding: {e}")))
+            })
+            .collect::<Result<_, _>>()?,
+        OutputFormat::Svg => document
+            .pages
+            .iter()
+            .map(|page| typst_svg::svg(page).into_bytes())
+            .collect(),
+    };
+
+    Ok(Rendered {
+        format,
+        files,
+        warnings,
+    })
+}
+
+/// Converts `time` into a PDF timestamp, keeping its offset from UTC.
+fn pdf_timestamp(time: time::OffsetDateTime) -> Option<Timestamp> {
+    let datetime = Datetime::from_ymd_hms(
+        time.year(),
+        time.month().into(),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+    )?;
+    Timestamp::new_local(datetime, time.offset().whole_minutes().into())
+}
+
+/// Parses every face of the given font files.
+fn load_fonts(files: Vec<(String, Vec<u8>)>) -> Result<Vec<Font>, AppError> {
+    let mut fonts = Vec::new();
+    for (name, data) in files {
+        let faces: Vec<_> = Font::iter(Bytes::new(data)).collect();
+        if faces.is_empty() {
+            return Err(AppError::InvalidData(format!(
+                "{name} is not a valid font file"
+            )));
+        }
+        fonts.extend(faces);
+    }
+    Ok(fonts)
+}
+
+/// Converts a JSON payload into the `sys.inputs` dictionary, under the `data` key.
+fn data_to_inputs(data: serde_json::Value) -> Result<Dict, AppError> {
+    let data = serde_json::from_value::<Value>(data)
+        .map_err(|error| AppError::InvalidData(error.to_string()))?;
+
+    let mut inputs = Dict::new();
+    inputs.insert("data".into(), data);
+    Ok(inputs)
 }
 
 //FIXME: Add From AppError for ErrorResponse instead
@@ -66,17 +402,60 @@ impl IntoResponse for AppError {
         #[derive(Serialize)]
         struct ErrorResponse {
             message: String,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            errors: Vec<FieldError>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            diagnostics: Vec<Diagnostic>,
         }
 
+        let mut errors = Vec::new();
+        let mut diagnostics = Vec::new();
+        let mut headers = HeaderMap::new();
         let (status, message) = match self {
-            AppError::CompilationError(error_details) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Template compilation failed: {}", error_details),
-            ),
+            AppError::CompilationError(compile_diagnostics) => {
+                diagnostics = compile_diagnostics;
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!(
+                        "Template compilation failed with {} diagnostic(s)",
+                        diagnostics.len()
+                    ),
+                )
+            }
             AppError::PdfGenerationError(error_details) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("PDF generation failed: {}", error_details),
             ),
+            AppError::InvalidData(error_details) => (
+                StatusCode::BAD_REQUEST,
+                format!("Template data is invalid: {}", error_details),
+            ),
+            AppError::ValidationError(field_errors) => {
+                errors = field_errors;
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("{} field(s) are invalid", errors.len()),
+                )
+            }
+            AppError::TemplateNotFound(template_id) => (
+                StatusCode::NOT_FOUND,
+                format!("No template registered with id `{}`", template_id),
+            ),
+            AppError::PayloadTooLarge(error_details) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Upload is too large: {}", error_details),
+            ),
+            AppError::PackageNotCached(package) => (
+                StatusCode::NOT_FOUND,
+                format!("Package `{}` is not in the cache", package),
+            ),
+            AppError::Overloaded { retry_after } => {
+                headers.insert(header::RETRY_AFTER, retry_after.as_secs().max(1).into());
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many documents are being rendered, please retry later".to_owned(),
+                )
+            }
             AppError::InternalServerError => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "An internal server error occurred".to_owned(),
@@ -85,13 +464,65 @@ impl IntoResponse for AppError {
 
         tracing::error!("PDF generation error: {}", message);
 
-        (status, AppJson(ErrorResponse { message })).into_response()
+        (
+            status,
+            headers,
+            AppJson(ErrorResponse {
+                message,
+                errors,
+                diagnostics,
+            }),
+        )
+            .into_response()
     }
 }
 
+/// JSON extractor and response whose rejections are reported like other [`AppError`]s.
 #[derive(FromRequest)]
 #[from_request(via(axum::Json), rejection(AppError))]
-struct AppJson<T>(T);
+pub struct AppJson<T>(pub T);
+
+impl From<JsonRejection> for AppError {
+    /// Reports bodies that don't match the expected type as a [`AppError::ValidationError`] of
+    /// the offending field, and all other rejections as [`AppError::InvalidData`].
+    fn from(rejection: JsonRejection) -> Self {
+        let JsonRejection::JsonDataError(error) = &rejection else {
+            return AppError::InvalidData(rejection.body_text());
+        };
+        let mut source = std::error::Error::source(error);
+        while let Some(current) = source {
+            if let Some(error) =
+                current.downcast_ref::<serde_path_to_error::Error<serde_json::Error>>()
+            {
+                return AppError::ValidationError(vec![field_error(error)]);
+            }
+            source = current.source();
+        }
+        AppError::InvalidData(rejection.body_text())
+    }
+}
+
+/// Describes a deserialization error by the path of the field, without the position in the body.
+fn field_error(error: &serde_path_to_error::Error<serde_json::Error>) -> FieldError {
+    let message = error.inner().to_string();
+    let message = match message.rsplit_once(" at line ") {
+        Some((message, _)) => message.to_owned(),
+        None => message,
+    };
+
+    // serde reports missing fields at the object that lacks them.
+    let mut field = error.path().to_string();
+    if let Some(missing) = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.strip_suffix('`'))
+    {
+        field = match field.as_str() {
+            "." => missing.to_owned(),
+            parent => format!("{parent}.{missing}"),
+        };
+    }
+    FieldError { field, message }
+}
 
 impl<T> IntoResponse for AppJson<T>
 where
@@ -104,6 +535,7 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::diagnostics::{DiagnosticSeverity, Location};
     #[test]
     fn pdf_generation_test() {
         let pdf_buf = super::template_to_pdf("Hello, Typst!".to_string()).expect("pdf gen");
@@ -118,4 +550,301 @@ mod tests {
             "PDF buffer should end with %%EOF"
         );
     }
+
+    #[test]
+    fn compilation_errors_are_resolved_to_lines() {
+        let template = "= Title\n\n#let x = 1\n#undefined-function(x)\n";
+
+        let Err(super::AppError::CompilationError(diagnostics)) =
+            super::template_to_pdf(template.to_string())
+        else {
+            panic!("template should fail to compile");
+        };
+
+        let [diagnostic] = diagnostics.as_slice() else {
+            panic!("expected a single diagnostic, got {diagnostics:?}");
+        };
+        assert_eq!(diagnostic.severity, DiagnosticSeverity::Error);
+        assert_eq!(diagnostic.message, "unknown variable: undefined-function");
+        assert_eq!(
+            diagnostic.location,
+            Some(Location {
+                file: "main.typ".to_string(),
+                line: 4,
+                column: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn warnings_are_returned_with_the_pdf() {
+        let template = r#"#text(font: "Definitely Not A Font")[Hello]"#;
+
+        let rendered =
+            super::template_to_pdf_with_warnings(template.to_string(), Default::default())
+                .expect("warnings should not fail the render");
+
+        assert!(rendered.pdf.starts_with(b"%PDF-"));
+        let [warning] = rendered.warnings.as_slice() else {
+            panic!("expected a single warning, got {:?}", rendered.warnings);
+        };
+        assert_eq!(warning.severity, DiagnosticSeverity::Warning);
+        assert!(warning.message.contains("unknown font family"));
+    }
+
+    #[test]
+    fn strict_mode_fails_on_warnings() {
+        let template = r#"#text(font: "Definitely Not A Font")[Hello]"#;
+        let options = super::RenderOptions {
+            strict: true,
+            ..Default::default()
+        };
+
+        let result = super::template_to_pdf_with_warnings(template.to_string(), options);
+
+        assert!(matches!(
+            result,
+            Err(super::AppError::CompilationError(diagnostics))
+                if diagnostics.iter().all(|d| d.severity == DiagnosticSeverity::Warning)
+        ));
+    }
+
+    #[test]
+    fn renders_one_image_per_page() {
+        let template = "Page one #pagebreak() Page two";
+
+        let png = super::render_template(
+            template.to_string(),
+            Default::default(),
+            super::OutputFormat::Png { dpi: 36.0 },
+        )
+        .expect("png render");
+        assert_eq!(png.files.len(), 2);
+        assert!(png.files.iter().all(|page| page.starts_with(b"\x89PNG")));
+
+        let svg = super::render_template(
+            template.to_string(),
+            Default::default(),
+            super::OutputFormat::Svg,
+        )
+        .expect("svg render");
+        assert_eq!(svg.files.len(), 2);
+        assert!(svg.files.iter().all(|page| page.starts_with(b"<svg")));
+    }
+
+    #[test]
+    fn rejects_out_of_range_dpi() {
+        let result = super::render_template(
+            "Hello".to_string(),
+            Default::default(),
+            super::OutputFormat::Png { dpi: 10_000.0 },
+        );
+
+        assert!(matches!(result, Err(super::AppError::InvalidData(_))));
+    }
+
+    #[test]
+    fn data_is_exposed_as_sys_inputs() {
+        let template = r#"#let data = sys.inputs.data
+#assert.eq(data.name, "O'Brien \\ #strong[Co]")
+#assert.eq(data.items.len(), 2)
+#data.name"#;
+        let options = super::RenderOptions {
+            data: Some(serde_json::json!({
+                "name": "O'Brien \\ #strong[Co]",
+                "items": [1, 2.5],
+            })),
+            ..Default::default()
+        };
+
+        super::template_to_pdf_with_options(template.to_string(), options)
+            .expect("data should be readable from sys.inputs");
+    }
+
+    #[test]
+    fn local_files_are_resolved_in_the_root() {
+        let root = tempfile::tempdir().expect("tempdir");
+        std::fs::write(root.path().join("header.typ"), "#let title = [Invoice]").expect("write");
+        let options = |template: &str| {
+            let options = super::RenderOptions {
+                root: Some(root.path().to_path_buf()),
+                ..Default::default()
+            };
+            super::template_to_pdf_with_options(template.to_string(), options)
+        };
+
+        options(
+            r#"#import "header.typ": title
+#title"#,
+        )
+        .expect("header.typ should be found in the root");
+
+        let Err(super::AppError::CompilationError(diagnostics)) =
+            options(r#"#image("assets/logo.png")"#)
+        else {
+            panic!("missing file should fail the render");
+        };
+        assert_eq!(
+            diagnostics[0].message,
+            "file not found (searched at assets/logo.png)"
+        );
+    }
+
+    #[test]
+    fn request_files_take_precedence_over_the_root() {
+        let root = tempfile::tempdir().expect("tempdir");
+        std::fs::write(root.path().join("note.txt"), "from the root").expect("write");
+        std::fs::write(root.path().join("other.txt"), "still here").expect("write");
+        let options = super::RenderOptions {
+            root: Some(root.path().to_path_buf()),
+            files: [("note.txt".to_string(), b"from the request".to_vec())].into(),
+            ..Default::default()
+        };
+
+        super::template_to_pdf_with_options(
+            r#"#assert.eq(read("note.txt"), "from the request")
+#assert.eq(read("/other.txt"), "still here")"#
+                .to_string(),
+            options,
+        )
+        .expect("files should be resolved through the overlay first");
+    }
+
+    #[test]
+    fn isolated_renders_only_see_their_files() {
+        let root = tempfile::tempdir().expect("tempdir");
+        std::fs::write(root.path().join("secret.txt"), "from the server").expect("write");
+        let options = super::RenderOptions {
+            root: Some(root.path().to_path_buf()),
+            files: [(
+                "project/lib/helpers.typ".to_string(),
+                b"#let x = 1".to_vec(),
+            )]
+            .into(),
+            main: Some("project/main.typ".to_string()),
+            isolated: true,
+            ..Default::default()
+        };
+
+        let Err(super::AppError::CompilationError(diagnostics)) =
+            super::template_to_pdf_with_options(
+                r#"#import "lib/helpers.typ": x
+#assert.eq(x, 1)
+#read("/secret.txt")"#
+                    .to_string(),
+                options,
+            )
+        else {
+            panic!("files on disk should not be readable");
+        };
+        let [diagnostic] = diagnostics.as_slice() else {
+            panic!("expected a single diagnostic, got {diagnostics:?}");
+        };
+        assert_eq!(
+            diagnostic.message,
+            "file not found (searched at secret.txt)"
+        );
+        assert_eq!(
+            diagnostic
+                .location
+                .as_ref()
+                .map(|location| location.file.as_str()),
+            Some("project/main.typ")
+        );
+    }
+
+    #[test]
+    fn render_options_debug_omits_file_contents() {
+        let options = super::RenderOptions {
+            files: [("logo.png".to_owned(), vec![137; 1000])].into(),
+            fonts: vec![("Corporate.otf".to_owned(), vec![0; 2000])],
+            ..Default::default()
+        };
+
+        let debug = format!("{options:?}");
+        assert!(debug.contains("logo.png (1000 bytes)"), "{debug}");
+        assert!(debug.contains("Corporate.otf (2000 bytes)"), "{debug}");
+        assert!(!debug.contains("137"), "{debug}");
+    }
+
+    #[test]
+    fn fixed_timestamps_render_identical_pdfs() {
+        let render = || {
+            let options = super::RenderOptions {
+                timestamp: Some(time::macros::datetime!(2024-02-29 23:30 -01:00)),
+                ident: Some("invoice-2024-001".to_owned()),
+                ..Default::default()
+            };
+            super::template_to_pdf_with_options(
+                "Issued on #datetime.today().display()".to_string(),
+                options,
+            )
+            .expect("pdf gen")
+        };
+
+        let pdf = render();
+        assert_eq!(pdf, render(), "renders are byte-identical");
+        let text = pdf_extract::extract_text_from_mem(&pdf).expect("text is extracted");
+        assert!(text.contains("Issued on 2024-02-29"), "{text}");
+        assert!(
+            pdf.windows(16).any(|window| window == b"D:20240229233000"),
+            "the PDF is created at the timestamp"
+        );
+    }
+
+    #[test]
+    fn today_is_taken_at_the_offset_of_the_timestamp_without_a_time_zone() {
+        let render = |timezone: Option<&str>, today: &str| {
+            let options = super::RenderOptions {
+                timestamp: Some(time::macros::datetime!(2024-02-29 23:30 -01:00)),
+                timezone: timezone.map(ToOwned::to_owned),
+                ..Default::default()
+            };
+            super::template_to_pdf_with_options(
+                format!(r#"#assert.eq(datetime.today().display(), "{today}")"#),
+                options,
+            )
+        };
+
+        render(None, "2024-02-29").expect("the offset of the timestamp is kept");
+        render(Some("Europe/Berlin"), "2024-03-01").expect("the time zone takes precedence");
+    }
+
+    #[test]
+    fn today_is_computed_in_the_time_zone() {
+        let render = |timezone: Option<&str>, template: &str| {
+            let options = super::RenderOptions {
+                timestamp: Some(time::macros::datetime!(2024-02-29 23:30 UTC)),
+                timezone: timezone.map(ToOwned::to_owned),
+                ..Default::default()
+            };
+            super::template_to_pdf_with_options(template.to_string(), options)
+        };
+
+        for (timezone, today) in [
+            (None, "2024-02-29"),
+            (Some("Asia/Kolkata"), "2024-03-01"),
+            (Some("America/St_Johns"), "2024-02-29"),
+            (Some("Pacific/Kiritimati"), "2024-03-01"),
+        ] {
+            render(
+                timezone,
+                &format!(r#"#assert.eq(datetime.today().display(), "{today}")"#),
+            )
+            .unwrap_or_else(|error| panic!("{timezone:?}: {error}"));
+        }
+
+        render(
+            Some("Asia/Kolkata"),
+            r#"#assert.eq(datetime.today(offset: -24).display(), "2024-02-28")
+#assert.eq(datetime.today(offset: 30).display(), "2024-03-02")"#,
+        )
+        .expect("explicit offsets are relative to UTC");
+
+        let error = render(Some("Mars/Olympus_Mons"), "").expect_err("zone is unknown");
+        assert!(
+            matches!(&error, super::AppError::InvalidData(message) if message.contains("Mars/Olympus_Mons")),
+            "{error:?}"
+        );
+    }
 }
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::Value;
+use typst::syntax::package::PackageSpec;
+
+use super::{AppError, german_invoice, letter};
+use crate::packages::imported_packages;
+
+/// A template that can be rendered by its id.
+#[derive(Debug, Clone, Serialize)]
+pub struct TemplateDefinition {
+    pub id: String,
+    pub description: String,
+    /// Typst source of the template.
+    #[serde(skip)]
+    pub source: String,
+    /// JSON schema of the data the template expects.
+    pub schema: Value,
+    /// Directory that the template's local files (images, includes) are resolved in.
+    #[serde(skip)]
+    pub root: Option<PathBuf>,
+}
+
+impl TemplateDefinition {
+    pub fn new(
+        id: impl Into<String>,
+        description: impl Into<String>,
+        source: impl Into<String>,
+        schema: Value,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            description: description.into(),
+            source: source.into(),
+            schema,
+            root: None,
+        }
+    }
+
+    /// Resolves the template's local files in `root` rather than the registry's root.
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+}
+
+/// Maps a `template_id` to the template that should be rendered for it.
+#[derive(Debug, Default)]
+pub struct TemplateRegistry {
+    templates: BTreeMap<String, TemplateDefinition>,
+    /// Root of templates without a root of their own, [`crate::default_root`] if unset.
+    root: Option<PathBuf>,
+}
+
+impl TemplateRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry containing every template shipped with this crate.
+    pub fn with_builtin_templates() -> Self {
+        let mut registry = Self::new();
+        registry.register(TemplateDefinition::new(
+            german_invoice::TEMPLATE_ID,
+            "German invoice (classy-german-invoice)",
+            german_invoice::GERMAN_INVOICE_TEMPLATE,
+            german_invoice::schema(),
+        ));
+        registry.register(TemplateDefinition::new(
+            letter::TEMPLATE_ID,
+            "Plain business letter",
+            letter::LETTER_TEMPLATE,
+            letter::schema(),
+        ));
+        registry
+    }
+
+    /// Resolves the local files of templates without a root of their own in `root`.
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// The directory that local files of `template` are resolved in, if not the default one.
+    pub fn root_of<'a>(&'a self, template: &'a TemplateDefinition) -> Option<&'a Path> {
+        template.root.as_deref().or(self.root.as_deref())
+    }
+
+    /// Adds a template, returning the one previously registered under the same id.
+    pub fn register(&mut self, template: TemplateDefinition) -> Option<TemplateDefinition> {
+        self.templates.insert(template.id.clone(), template)
+    }
+
+    pub fn get(&self, id: &str) -> Result<&TemplateDefinition, AppError> {
+        self.templates
+            .get(id)
+            .ok_or_else(|| AppError::TemplateNotFound(id.to_owned()))
+    }
+
+    /// All registered templates, ordered by id.
+    pub fn templates(&self) -> impl Iterator<Item = &TemplateDefinition> {
+        self.templates.values()
+    }
+
+    /// Every package imported by a registered template.
+    pub fn packages(&self) -> Vec<PackageSpec> {
+        let mut packages = Vec::new();
+        for package in self.templates().flat_map(|t| imported_packages(&t.source)) {
+            if !packages.contains(&package) {
+                packages.push(package);
+            }
+        }
+        packages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::{RenderOptions, template_to_pdf_with_options};
+
+    #[test]
+    fn builtin_templates_are_registered() {
+        let registry = TemplateRegistry::with_builtin_templates();
+        let ids: Vec<_> = registry.templates().map(|t| t.id.as_str()).collect();
+
+        assert_eq!(ids, [german_invoice::TEMPLATE_ID, letter::TEMPLATE_ID]);
+    }
+
+    #[test]
+    fn builtin_template_packages_are_pinned() {
+        let registry = TemplateRegistry::with_builtin_templates();
+        let packages: Vec<_> = registry.packages().iter().map(|p| p.to_string()).collect();
+
+        assert_eq!(packages, ["@preview/classy-german-invoice:0.3.1"]);
+    }
+
+    #[test]
+    fn unknown_template_id_is_not_found() {
+        let registry = TemplateRegistry::with_builtin_templates();
+
+        assert!(matches!(
+            registry.get("does-not-exist"),
+            Err(AppError::TemplateNotFound(id)) if id == "does-not-exist"
+        ));
+    }
+
+    #[test]
+    fn template_roots_override_the_registry_root() {
+        let mut registry = TemplateRegistry::new();
+        assert_eq!(
+            registry.root_of(&TemplateDefinition::new("a", "", "", Value::Null)),
+            None
+        );
+
+        registry = registry.with_root("assets");
+        registry.register(TemplateDefinition::new("shared", "", "", Value::Null));
+        registry.register(TemplateDefinition::new("own", "", "", Value::Null).with_root("own"));
+
+        let root_of = |id| registry.root_of(registry.get(id).expect("registered"));
+        assert_eq!(root_of("shared"), Some(Path::new("assets")));
+        assert_eq!(root_of("own"), Some(Path::new("own")));
+    }
+
+    #[test]
+    fn registered_letter_renders() {
+        let registry = TemplateRegistry::with_builtin_templates();
+        let letter = registry.get(letter::TEMPLATE_ID).expect("letter template");
+
+        let party = serde_json::json!({
+            "name": "Kerstin Humm",
+            "street": "Straße der Privatsphäre und Stille 1",
+            "zip_code": "54321",
+            "city": "Potsdam",
+        });
+        let options = RenderOptions {
+            data: Some(serde_json::json!({
+                "sender": party,
+                "recipient": party,
+                "date": "2024-09-03",
+                "subject": "Ihre Anfrage",
+                "body": "Vielen Dank für Ihre Anfrage.",
+            })),
+            ..Default::default()
+        };
+
+        let pdf_buf =
+            template_to_pdf_with_options(letter.source.clone(), options).expect("pdf gen");
+        assert!(pdf_buf.starts_with(b"%PDF-"));
+    }
+}
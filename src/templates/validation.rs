@@ -0,0 +1,122 @@
+use serde::Serialize;
+
+use super::AppError;
+
+/// A single invalid field of a request payload.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    /// Path to the field, e.g. `bank_account.iban` or `items[1].price`.
+    pub field: String,
+    pub message: String,
+}
+
+/// Collects every [`FieldError`] of a payload instead of stopping at the first one.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn error(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    pub fn non_empty(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.error(field, "must not be empty");
+        }
+    }
+
+    /// Expects a calendar date in the `YYYY-MM-DD` format.
+    pub fn date(&mut self, field: &str, value: &str) {
+        if parse_date(value).is_none() {
+            self.error(field, format!("`{value}` is not a valid YYYY-MM-DD date"));
+        }
+    }
+
+    pub fn iban(&mut self, field: &str, value: &str) {
+        if let Err(message) = check_iban(value) {
+            self.error(field, message);
+        }
+    }
+
+    pub fn bic(&mut self, field: &str, value: &str) {
+        let bic: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+        let well_formed = bic.is_ascii()
+            && matches!(bic.len(), 8 | 11)
+            && bic[..6].chars().all(|c| c.is_ascii_uppercase())
+            && bic[6..]
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        if !well_formed {
+            self.error(field, format!("`{value}` is not a valid BIC"));
+        }
+    }
+
+    pub fn finish(self) -> Result<(), AppError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::ValidationError(self.errors))
+        }
+    }
+}
+
+/// Parses a `YYYY-MM-DD` date.
+pub fn parse_date(value: &str) -> Option<time::Date> {
+    let mut parts = value.split('-');
+    let (Some(year), Some(month), Some(day), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+
+    let month = time::Month::try_from(month.parse::<u8>().ok()?).ok()?;
+    time::Date::from_calendar_date(year.parse().ok()?, month, day.parse().ok()?).ok()
+}
+
+/// Checks the length, characters and ISO 13616 check digits of an IBAN.
+fn check_iban(value: &str) -> Result<(), String> {
+    let iban: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    if !(15..=34).contains(&iban.len()) {
+        return Err(format!(
+            "IBAN must have 15 to 34 characters, got {}",
+            iban.len()
+        ));
+    }
+    if !iban
+        .chars()
+        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        || !iban[..2].chars().all(|c| c.is_ascii_uppercase())
+    {
+        return Err(format!("`{value}` is not a valid IBAN"));
+    }
+
+    // Move the country code and check digits to the end, then compute mod 97 digit by digit.
+    let remainder = iban[4..]
+        .chars()
+        .chain(iban[..4].chars())
+        .fold(0u32, |remainder, c| {
+            let value = c.to_digit(36).expect("IBAN is alphanumeric");
+            if value < 10 {
+                (remainder * 10 + value) % 97
+            } else {
+                (remainder * 100 + value) % 97
+            }
+        });
+    if remainder != 1 {
+        return Err("IBAN check digits do not match".to_owned());
+    }
+
+    Ok(())
+}
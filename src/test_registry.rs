@@ -0,0 +1,176 @@
+//! A minimal in-process stand-in for a Typst package registry, to exercise package downloads
+//! without network access.
+//!
+//! Packages are served as `{url}/{namespace}/{name}-{version}.tar.gz`, like on
+//! `https://packages.typst.org`.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct State {
+    archives: HashMap<String, Vec<u8>>,
+    /// Number of upcoming requests answered with a 500.
+    failures: usize,
+    /// Paths of all requests received so far.
+    requests: Vec<String>,
+}
+
+/// An HTTP registry listening on a random local port until dropped.
+pub struct TestRegistry {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl TestRegistry {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind test registry");
+        let address = listener.local_addr().expect("Test registry has an address");
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        std::thread::spawn({
+            let state = Arc::clone(&state);
+            let shutdown = Arc::clone(&shutdown);
+            move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::Acquire) {
+                        return;
+                    }
+                    if let Ok(stream) = stream {
+                        _ = serve(stream, &state);
+                    }
+                }
+            }
+        });
+
+        Self {
+            address,
+            state,
+            shutdown,
+        }
+    }
+
+    /// Base URL to use as the registry URL.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Serves `archive` as the given package.
+    pub fn add_package(&self, namespace: &str, name: &str, version: &str, archive: Vec<u8>) {
+        self.state()
+            .archives
+            .insert(format!("/{namespace}/{name}-{version}.tar.gz"), archive);
+    }
+
+    /// Answers the next `count` requests with a server error.
+    pub fn fail_next(&self, count: usize) {
+        self.state().failures = count;
+    }
+
+    /// Paths of all requests received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state().requests.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("Test registry state is poisoned")
+    }
+}
+
+impl Drop for TestRegistry {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+        // Wake up the accept loop so that it notices the shutdown.
+        _ = TcpStream::connect(self.address);
+    }
+}
+
+fn serve(mut stream: TcpStream, state: &Mutex<State>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_owned();
+
+    let (status, body) = {
+        let mut state = state.lock().expect("Test registry state is poisoned");
+        state.requests.push(path.clone());
+        if state.failures > 0 {
+            state.failures -= 1;
+            ("500 Internal Server Error", Vec::new())
+        } else if let Some(archive) = state.archives.get(&path) {
+            ("200 OK", archive.clone())
+        } else {
+            ("404 Not Found", Vec::new())
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+/// Builds a `.tar.gz` package archive containing `files`, given as `(path, content)`.
+pub fn package_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, path, *content)
+            .expect("Failed to append file to archive");
+    }
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .expect("Failed to finish archive")
+}
+
+/// Gzips a tar archive of `(path, type, content)` entries, written without the checks of
+/// `tar::Builder`, like a malicious registry could.
+pub fn crafted_archive(entries: &[(&str, tar::EntryType, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, entry_type, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().expect("gnu header").name[..path.len()]
+            .copy_from_slice(path.as_bytes());
+        header.set_entry_type(*entry_type);
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            header.set_link_name("lib.typ").expect("valid link name");
+        }
+        header.set_cksum();
+        builder
+            .append(&header, *content)
+            .expect("entry is appended");
+    }
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    std::io::Write::write_all(
+        &mut encoder,
+        &builder.into_inner().expect("tar is finished"),
+    )
+    .expect("archive is compressed");
+    encoder.finish().expect("archive is compressed")
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+use jiff::tz::TimeZone;
+
+use crate::templates::AppError;
+
+/// Time zones that `datetime.today()` is computed in, unless a render names its own.
+#[derive(Debug, Clone, Default)]
+pub struct TimezoneConfig {
+    /// Zone of renders without a zone of their own or of their tenant, UTC if not set.
+    pub default: Option<TimeZone>,
+
+    /// Zones of tenants, keyed by tenant.
+    pub tenants: HashMap<String, TimeZone>,
+}
+
+impl TimezoneConfig {
+    /// Reads `TIMEZONE` and `TENANT_TIMEZONES`, given as `tenant=zone` pairs separated by commas,
+    /// e.g. `acme=Asia/Kolkata,globex=America/St_Johns`. Unknown zones are logged and ignored.
+    pub fn from_env() -> Self {
+        fn zone(name: &str) -> Option<TimeZone> {
+            TimeZone::get(name)
+                .inspect_err(|_| tracing::warn!("Ignoring unknown time zone `{name}`"))
+                .ok()
+        }
+
+        Self {
+            default: std::env::var("TIMEZONE").ok().and_then(|name| zone(&name)),
+            tenants: std::env::var("TENANT_TIMEZONES")
+                .map(|value| {
+                    value
+                        .split(',')
+                        .filter_map(|pair| {
+                            let (tenant, name) = pair.split_once('=')?;
+                            Some((tenant.trim().to_owned(), zone(name.trim())?))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Looks up an IANA time zone such as `Europe/Berlin` in the time zone database.
+pub fn parse_timezone(name: &str) -> Result<TimeZone, AppError> {
+    TimeZone::get(name).map_err(|_| AppError::InvalidData(format!("unknown time zone `{name}`")))
+}
@@ -1,49 +1,52 @@
 #import "@preview/classy-german-invoice:0.3.1": invoice
 
+// Invoice data, passed in as JSON through `sys.inputs.data`
+#let data = sys.inputs.data
+
+// YYYY-MM-DD
+#let (year, month, day) = data.date.split("-").map(int)
+
 #show: invoice(
   // Invoice number
-  "2023-001",
+  data.invoice_number,
   // Invoice date
-  datetime(year: 2024, month: 09, day: 03),
+  datetime(year: year, month: month, day: day),
   // Items
-  (
-    (
-      description: "The first service provided. The first service provided. The first service provided",
-      price: 200,
-    ),
-    (
-      description: "The second service provided",
-      price: 150.2
-    ),
-  ),
+  data.items.map(item => (
+    description: item.description,
+    price: item.price,
+  )),
   // Author
   (
-    name: "Kerstin Humm",
-    street: "Straße der Privatsphäre und Stille 1",
-    zip: "54321",
-    city: "Potsdam",
-    tax_nr: "12345/67890",
-    // optional signature, can be omitted
-  //  signature: image("example_signature.png", width: 5em)
-  ),
+    name: data.author.name,
+    street: data.author.address.street,
+    zip: data.author.address.zip_code,
+    city: data.author.address.city,
+    tax_nr: data.author.address.tax_nb,
+  ) + if data.author.address.signature != none {
+    // optional signature, uploaded with the request and mounted at this path
+    (signature: image("author-signature", width: 5em))
+  } else {
+    (:)
+  },
   // Recipient
   (
-    name: "Erika Mustermann",
-    street: "Musterallee",
-    zip: "12345",
-    city: "Musterstadt",
+    name: data.recipient.name,
+    street: data.recipient.address.street,
+    zip: data.recipient.address.zip_code,
+    city: data.recipient.address.city,
   ),
   // Bank account
   (
-    name: "Todd Name",
-    bank: "Deutsche Postbank AG",
-    iban: "DE89370400440532013000",
-    bic: "PBNKDEFF",
+    name: data.bank_account.name,
+    bank: data.bank_account.bank_name,
+    iban: data.bank_account.iban,
+    bic: data.bank_account.bic,
     // There is currently only one gendered term in this template.
     // You can overwrite it, or omit it and just choose the default.
     gender: (account_holder: "Kontoinhaberin")
   ),
   // Umsatzsteuersatz (VAT)
-  vat: 0.19,
-  kleinunternehmer: true,
+  vat: data.vat_rate / 100,
+  kleinunternehmer: data.is_micro_business,
 )
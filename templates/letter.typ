@@ -0,0 +1,26 @@
+// Letter data, passed in as JSON through `sys.inputs.data`
+#let data = sys.inputs.data
+
+// YYYY-MM-DD
+#let (year, month, day) = data.date.split("-").map(int)
+
+#set page(paper: "a4", margin: (x: 2.5cm, top: 3cm, bottom: 2.5cm))
+#set text(size: 11pt)
+
+// Sender
+#text(size: 8pt)[#data.sender.name · #data.sender.street · #data.sender.zip_code #data.sender.city]
+
+// Recipient
+#data.recipient.name \
+#data.recipient.street \
+#data.recipient.zip_code #data.recipient.city
+
+#v(2cm)
+
+#align(right)[#data.sender.city, #datetime(year: year, month: month, day: day).display("[day].[month].[year]")]
+
+*Betreff: #data.subject*
+
+#data.body
+
+#data.sender.name
@@ -0,0 +1,33 @@
+// Test stub with the signature of `invoice` from `@preview/classy-german-invoice:0.3.1`. It is NOT
+// the published package and renders no invoice: it records the arguments it receives as metadata
+// labelled `<invoice-arguments>`, so that tests can check how `templates/german_invoice.typ` maps
+// the invoice data onto the package's parameters. Named arguments default to `none`, so that
+// omitted ones are recorded as such.
+
+#let invoice(
+  invoice-nr,
+  invoice-date,
+  items,
+  author,
+  recipient,
+  bank-account,
+  vat: none,
+  kleinunternehmer: none,
+) = body => {
+  [#metadata((
+    invoice-nr: invoice-nr,
+    invoice-date: invoice-date,
+    items: items,
+    author: author,
+    recipient: recipient,
+    bank-account: bank-account,
+    vat: vat,
+    kleinunternehmer: kleinunternehmer,
+  )) <invoice-arguments>]
+
+  // Shown, so that the signature image has to be loaded.
+  if "signature" in author {
+    author.signature
+  }
+  body
+}